@@ -12,8 +12,7 @@ pub fn create_hello_world() {
         0x000A, // "Hello world!\n" string with its null terminator.
     ]
     .into_iter()
-    .map(|a| (a as u16).to_be_bytes())
-    .flatten()
+    .flat_map(|a| (a as u16).to_be_bytes())
     .collect();
 
     std::fs::write("./examples/hello_world.obj", program).unwrap();
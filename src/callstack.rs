@@ -0,0 +1,318 @@
+//! A shadow call stack, following the subroutines a program enters and
+//! leaves.
+//!
+//! The LC-3 has no call stack of its own: JSR and JSRR save the return
+//! address in R7 and RET jumps back to it. [`CallStack`] follows those
+//! instructions instead. JSR and JSRR enter a subroutine and RET leaves it,
+//! while TRAPs jumping to a service routine, interrupts and exceptions enter
+//! a handler that RTI leaves. Every frame remembers where it returns to, and
+//! a RET only leaves it when it jumps there, so that a RET used as a plain
+//! jump doesn't leave a frame it isn't returning from. Trap routines that
+//! return with RET, as with
+//! [`TrapMode::VectorTable`](crate::TrapMode::VectorTable), leave their frame
+//! that way too.
+//!
+//! [`Vm::call_stack`](crate::Vm::call_stack) is the stack of the running
+//! program.
+
+use super::state::{State, STACK_POINTER};
+use super::vm::StepOutcome;
+
+/// RET, i.e. JMP R7.
+const RET: u16 = 0xC1C0;
+/// Deeper calls forget the outermost ones, so that a program using JSR as a
+/// jump can't grow the stack forever.
+pub const MAX_DEPTH: usize = 4096;
+
+/// A subroutine or handler being executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// Address it was entered at
+    pub entry: u16,
+    /// Address execution goes back to when it is left: the instruction after
+    /// the call, or the one an interrupt came before
+    pub return_address: u16,
+    /// Whether it is a trap, interrupt or exception handler, left with RTI
+    pub handler: bool,
+    /// Instructions executed before it was entered
+    pub entered_at: u64,
+}
+
+/// How a step changed the [`CallStack`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallEvent {
+    /// A frame was pushed
+    Entered,
+    /// The frame was popped
+    Left(Frame),
+}
+
+/// The subroutines and handlers a program is in.
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+    instructions: u64,
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack::default()
+    }
+
+    /// The frames being executed, outermost first. Code running outside of
+    /// any subroutine has none.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Instructions executed so far.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Follows a step that executed `instruction` at `pc` (or took an
+    /// interrupt) and left the machine in `state`.
+    pub(crate) fn follow(
+        &mut self,
+        pc: u16,
+        instruction: u16,
+        outcome: &StepOutcome,
+        state: &State,
+    ) -> Option<CallEvent> {
+        let next_pc = state.pc;
+        // Interrupts and exceptions push the PC they return to
+        let pushed_pc = || state.memory[state.registers[STACK_POINTER] as usize];
+
+        if let StepOutcome::Interrupted(_) = outcome {
+            return Some(self.enter(next_pc, pushed_pc(), true));
+        }
+
+        self.instructions += 1;
+        let opcode = instruction >> 12;
+        match outcome {
+            StepOutcome::Exception(_) => Some(self.enter(next_pc, pushed_pc(), true)),
+            StepOutcome::Faulted(_) => None,
+            _ if (opcode == 0x4 || opcode == 0xF) && next_pc != pc.wrapping_add(1) => {
+                Some(self.enter(next_pc, pc.wrapping_add(1), opcode == 0xF))
+            }
+            _ if instruction == RET => match self.frames.last() {
+                Some(frame) if frame.return_address == next_pc => self.leave(),
+                _ => None,
+            },
+            _ if opcode == 0x8 => match self.frames.last() {
+                Some(frame) if frame.handler => self.leave(),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Takes back what a step did to the stack, given the event
+    /// [`CallStack::follow`] returned for it. Frames forgotten for going
+    /// deeper than [`MAX_DEPTH`] don't come back.
+    pub(crate) fn undo(&mut self, interrupted: bool, event: Option<CallEvent>) {
+        if !interrupted {
+            self.instructions -= 1;
+        }
+
+        match event {
+            Some(CallEvent::Entered) => {
+                self.frames.pop();
+            }
+            Some(CallEvent::Left(frame)) => self.frames.push(frame),
+            None => {}
+        }
+    }
+
+    fn enter(&mut self, entry: u16, return_address: u16, handler: bool) -> CallEvent {
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.remove(0);
+        }
+        self.frames.push(Frame {
+            entry,
+            return_address,
+            handler,
+            entered_at: self.instructions,
+        });
+
+        CallEvent::Entered
+    }
+
+    fn leave(&mut self) -> Option<CallEvent> {
+        self.frames.pop().map(CallEvent::Left)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::MemoryConsole;
+    use crate::Vm;
+
+    /// A call stack and the machine whose steps it follows.
+    struct Program {
+        stack: CallStack,
+        state: State,
+    }
+
+    impl Program {
+        fn new() -> Program {
+            Program {
+                stack: CallStack::new(),
+                state: State::with_console(Box::new(MemoryConsole::new(&[]))),
+            }
+        }
+
+        /// Follows `instruction` at `pc` executing normally and jumping to
+        /// `next_pc`.
+        fn follow(&mut self, pc: u16, instruction: u16, next_pc: u16) -> Option<CallEvent> {
+            self.state.pc = next_pc;
+            self.stack
+                .follow(pc, instruction, &StepOutcome::Executed, &self.state)
+        }
+    }
+
+    #[test]
+    fn subroutines_are_left_by_ret_and_trap_routines_by_rti() {
+        let mut program = Program::new();
+
+        // JSR #2, then TRAP x22 from the subroutine.
+        assert_eq!(
+            program.follow(0x3000, 0x4802, 0x3003),
+            Some(CallEvent::Entered)
+        );
+        assert_eq!(
+            program.follow(0x3003, 0xF022, 0x0400),
+            Some(CallEvent::Entered)
+        );
+        let subroutine = Frame {
+            entry: 0x3003,
+            return_address: 0x3001,
+            handler: false,
+            entered_at: 1,
+        };
+        let trap_routine = Frame {
+            entry: 0x0400,
+            return_address: 0x3004,
+            handler: true,
+            entered_at: 2,
+        };
+        assert_eq!(program.stack.frames(), [subroutine, trap_routine]);
+
+        assert_eq!(
+            program.follow(0x0400, 0x8000, 0x3004),
+            Some(CallEvent::Left(trap_routine))
+        );
+        assert_eq!(
+            program.follow(0x3004, RET, 0x3001),
+            Some(CallEvent::Left(subroutine))
+        );
+
+        assert_eq!(program.follow(0x3001, RET, 0x3001), None);
+        assert!(program.stack.frames().is_empty());
+        assert_eq!(program.stack.instructions(), 5);
+    }
+
+    #[test]
+    fn trap_routines_linked_through_r7_are_left_by_ret() {
+        let mut program = Program::new();
+
+        program.follow(0x3000, 0xF022, 0x0400);
+
+        // A RET inside the routine jumping elsewhere doesn't leave it.
+        assert_eq!(program.follow(0x0400, RET, 0x0410), None);
+        assert!(matches!(
+            program.follow(0x0410, RET, 0x3001),
+            Some(CallEvent::Left(_))
+        ));
+    }
+
+    #[test]
+    fn rti_does_not_leave_a_subroutine() {
+        let mut program = Program::new();
+
+        program.follow(0x3000, 0x4802, 0x3003);
+        assert_eq!(program.follow(0x3003, 0x8000, 0x3004), None);
+        assert_eq!(program.stack.frames().len(), 1);
+    }
+
+    #[test]
+    fn ret_only_leaves_the_frame_it_returns_from() {
+        let mut program = Program::new();
+
+        // JSR #2, and in the subroutine JSR #0 to load R7 with the PC, which
+        // enters nothing.
+        program.follow(0x3000, 0x4802, 0x3003);
+        assert_eq!(program.follow(0x3003, 0x4800, 0x3004), None);
+
+        // So a RET jumping back there isn't returning from the subroutine.
+        assert_eq!(program.follow(0x3006, RET, 0x3004), None);
+        assert_eq!(program.stack.frames().len(), 1);
+        assert!(matches!(
+            program.follow(0x3007, RET, 0x3001),
+            Some(CallEvent::Left(_))
+        ));
+    }
+
+    #[test]
+    fn interrupts_enter_a_handler_returning_to_the_interrupted_instruction() {
+        let mut program = Program::new();
+        // The PC the interrupt pushed
+        program.state.registers[STACK_POINTER] = 0x2FFE;
+        program.state.memory[0x2FFE] = 0x3000;
+        program.state.pc = 0x1000;
+
+        let event = program.stack.follow(
+            0x3000,
+            0x0FFF,
+            &StepOutcome::Interrupted(0x80),
+            &program.state,
+        );
+        assert_eq!(event, Some(CallEvent::Entered));
+        assert_eq!(program.stack.instructions(), 0);
+        assert!(program.stack.frames()[0].handler);
+        assert_eq!(program.stack.frames()[0].return_address, 0x3000);
+
+        assert!(matches!(
+            program.follow(0x1000, 0x8000, 0x3000),
+            Some(CallEvent::Left(_))
+        ));
+    }
+
+    #[test]
+    fn undoing_a_step_restores_the_stack() {
+        let mut program = Program::new();
+        let entered = program.follow(0x3000, 0x4802, 0x3003);
+        let frames = program.stack.frames().to_vec();
+        let left = program.follow(0x3003, RET, 0x3001);
+
+        program.stack.undo(false, left);
+        assert_eq!(program.stack.frames(), frames);
+        program.stack.undo(false, entered);
+        assert!(program.stack.frames().is_empty());
+        assert_eq!(program.stack.instructions(), 0);
+    }
+
+    #[test]
+    fn the_vm_follows_its_program_backwards_and_forwards() {
+        let mut vm = Vm::with_console(Box::new(MemoryConsole::new(&[])));
+        vm.load_image(&[
+            0x3000, // origin
+            0x4801, // JSR SUBROUTINE
+            0xF025, // HALT
+            0xC1C0, // SUBROUTINE: RET
+        ])
+        .unwrap();
+        vm.record_history(16);
+
+        vm.step();
+        assert_eq!(vm.call_stack().frames()[0].entry, 0x3002);
+        assert!(vm.step_back());
+        assert!(vm.call_stack().frames().is_empty());
+
+        vm.step();
+        vm.step();
+        assert!(vm.call_stack().frames().is_empty());
+        assert_eq!(vm.call_stack().instructions(), 2);
+    }
+}
@@ -0,0 +1,179 @@
+//! The keyboard and display the trap routines talk to.
+//!
+//! [`TerminalConsole`] reads from stdin and writes to stdout, while
+//! [`MemoryConsole`] feeds a program scripted input and captures everything it
+//! prints, which is what tests and embedders usually want.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Write;
+use std::rc::Rc;
+
+/// Byte-oriented console I/O used by the trap routines and the keyboard
+/// registers.
+pub trait Console {
+    /// Reads a byte, blocking until one is available. Returns `None` once the
+    /// input is exhausted and no more bytes will ever come.
+    fn read_byte(&mut self) -> std::io::Result<Option<u8>>;
+    /// Whether [`Console::read_byte`] would return without blocking, either
+    /// with a byte or because the input is exhausted.
+    fn poll_key(&mut self) -> std::io::Result<bool>;
+    /// Writes a byte to the display. It may be buffered until [`Console::flush`].
+    fn write_byte(&mut self, byte: u8) -> std::io::Result<()>;
+    /// Makes sure everything written so far is displayed.
+    fn flush(&mut self) -> std::io::Result<()>;
+}
+
+impl fmt::Debug for dyn Console {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Console")
+    }
+}
+
+/// Console backed by the process' stdin and stdout.
+///
+/// Stdin is read directly from its file descriptor, one byte at a time, so
+/// that [`Console::poll_key`] never misses input sitting in a buffer. Putting
+/// the terminal in non-canonical mode is up to the caller.
+#[derive(Debug, Default)]
+pub struct TerminalConsole;
+
+impl TerminalConsole {
+    pub fn new() -> TerminalConsole {
+        TerminalConsole
+    }
+}
+
+impl Console for TerminalConsole {
+    fn read_byte(&mut self) -> std::io::Result<Option<u8>> {
+        let mut buffer = [0; 1];
+
+        loop {
+            match nix::unistd::read(0, &mut buffer) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(buffer[0])),
+                Err(nix::errno::Errno::EINTR) => continue,
+                Err(error) => return Err(error.into()),
+            }
+        }
+    }
+
+    fn poll_key(&mut self) -> std::io::Result<bool> {
+        let mut readfds = nix::sys::select::FdSet::new();
+        readfds.insert(0);
+        let mut timeout: nix::sys::time::TimeVal = nix::sys::time::TimeValLike::seconds(0);
+
+        let ready = nix::sys::select::select(1, &mut readfds, None, None, &mut timeout)?;
+
+        Ok(ready != 0)
+    }
+
+    fn write_byte(&mut self, byte: u8) -> std::io::Result<()> {
+        std::io::stdout().write_all(&[byte])
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stdout().flush()
+    }
+}
+
+/// In-memory console with scripted input and captured output.
+///
+/// A console created with [`MemoryConsole::new`] reaches the end of its input
+/// once the script is consumed. One created with
+/// [`MemoryConsole::interactive`] instead keeps the program waiting for more
+/// input until [`MemoryConsole::close_input`] is called.
+///
+/// Clones share the same buffers, so a clone can be handed to the VM while
+/// the original is kept around to inspect the output:
+///
+/// ```
+/// use lc3::console::MemoryConsole;
+///
+/// let console = MemoryConsole::new(b"y");
+/// let mut vm = lc3::Vm::with_console(Box::new(console.clone()));
+/// // GETC, OUT, HALT
+/// vm.load_image(&[0x3000, 0xF020, 0xF021, 0xF025]).unwrap();
+/// vm.run().unwrap();
+///
+/// assert_eq!(console.output(), b"y");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MemoryConsole {
+    buffers: Rc<RefCell<Buffers>>,
+}
+
+#[derive(Debug, Default)]
+struct Buffers {
+    input: VecDeque<u8>,
+    input_closed: bool,
+    output: Vec<u8>,
+}
+
+impl MemoryConsole {
+    /// Creates a console that will hand out `input` one byte at a time, and
+    /// then report the end of the input.
+    pub fn new(input: &[u8]) -> MemoryConsole {
+        let console = MemoryConsole::interactive();
+        console.push_input(input);
+        console.close_input();
+
+        console
+    }
+
+    /// Creates a console with no input yet. Reads wait until bytes are pushed
+    /// with [`MemoryConsole::push_input`].
+    pub fn interactive() -> MemoryConsole {
+        MemoryConsole::default()
+    }
+
+    /// Appends bytes to the input still waiting to be read.
+    pub fn push_input(&self, input: &[u8]) {
+        self.buffers.borrow_mut().input.extend(input);
+    }
+
+    /// Marks the input as finished: once what was pushed so far is consumed,
+    /// reads report the end of the input.
+    pub fn close_input(&self) {
+        self.buffers.borrow_mut().input_closed = true;
+    }
+
+    /// Everything written to the console so far.
+    pub fn output(&self) -> Vec<u8> {
+        self.buffers.borrow().output.clone()
+    }
+
+    /// Takes everything written to the console so far, leaving it empty.
+    pub fn take_output(&self) -> Vec<u8> {
+        std::mem::take(&mut self.buffers.borrow_mut().output)
+    }
+}
+
+impl Console for MemoryConsole {
+    fn read_byte(&mut self) -> std::io::Result<Option<u8>> {
+        let mut buffers = self.buffers.borrow_mut();
+
+        match buffers.input.pop_front() {
+            Some(byte) => Ok(Some(byte)),
+            None if buffers.input_closed => Ok(None),
+            None => Err(std::io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn poll_key(&mut self) -> std::io::Result<bool> {
+        let buffers = self.buffers.borrow();
+
+        Ok(!buffers.input.is_empty() || buffers.input_closed)
+    }
+
+    fn write_byte(&mut self, byte: u8) -> std::io::Result<()> {
+        self.buffers.borrow_mut().output.push(byte);
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
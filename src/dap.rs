@@ -0,0 +1,859 @@
+//! A [Debug Adapter Protocol](https://microsoft.github.io/debug-adapter-protocol/)
+//! server speaking JSON over stdin and stdout, so editors like VS Code can
+//! debug object files.
+//!
+//! The `launch` request takes the object files to load as `program` (a path
+//! or a list of paths), `os` to load the bundled operating system first,
+//! `input` for the text the program reads from the keyboard and
+//! `stopOnEntry`. What the program prints is sent as `output` events.
+//!
+//! Object files have no source, so the first program gets a listing of its
+//! disassembly as a source, one line per word: breakpoints set on its lines
+//! and instruction breakpoints on any address both work. Like in the GDB
+//! server, memory is addressed by word and every word is two bytes, most
+//! significant first. Writing memory forgets the steps recorded so far, like
+//! the debugger's `set` does.
+
+use super::console::MemoryConsole;
+use super::disassembler::disassemble_instruction;
+use super::state::Privilege;
+use super::vm::{load_image_file, StepOutcome, Vm, INPUT_POLL_INTERVAL};
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+
+/// The only thread there is.
+const THREAD_ID: u64 = 1;
+/// `sourceReference` of the program listing.
+const LISTING_REFERENCE: u64 = 1;
+/// `variablesReference` of the registers and of the condition codes.
+const REGISTERS_REFERENCE: u64 = 1;
+const CONDITION_CODES_REFERENCE: u64 = 2;
+/// Requests that run the program.
+const RUN_COMMANDS: [&str; 7] = [
+    "configurationDone",
+    "continue",
+    "next",
+    "stepIn",
+    "stepOut",
+    "stepBack",
+    "reverseContinue",
+];
+/// How many steps are recorded for stepping back.
+const HISTORY_CAPACITY: usize = 1 << 20;
+/// How many instructions run between checks for a `pause` while running.
+const PAUSE_CHECK_INTERVAL: u32 = 1024;
+/// Frames deeper than this are not shown in stack traces.
+const MAX_FRAMES: usize = 64;
+
+/// How far to run for a `continue`, step or step back request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Run {
+    Step,
+    Continue,
+    /// Until the PC reaches the given address, which steps out of or over a
+    /// subroutine
+    Until(u16),
+    StepBack,
+    ReverseContinue,
+}
+
+/// A launched program.
+#[derive(Debug)]
+struct Session {
+    vm: Vm,
+    console: MemoryConsole,
+    breakpoints: BTreeSet<u16>,
+    /// Origin and length of the first program, shown as its source
+    listing: Option<(u16, u16)>,
+    stop_on_entry: bool,
+    terminated: bool,
+}
+
+/// Serves one debugging session to an editor.
+pub struct DapServer<W: Write> {
+    output: W,
+    sequence: u64,
+    session: Option<Session>,
+    /// Set when the editor disconnects while the program runs
+    disconnected: bool,
+}
+
+impl<W: Write> DapServer<W> {
+    /// Creates a server sending its messages to `output`.
+    pub fn new(output: W) -> DapServer<W> {
+        DapServer {
+            output,
+            sequence: 0,
+            session: None,
+            disconnected: false,
+        }
+    }
+
+    /// Handles the requests read from `input` until the editor disconnects.
+    pub fn serve<R: BufRead + Send + 'static>(&mut self, input: R) -> std::io::Result<()> {
+        // Messages are read on their own thread, so that a running program
+        // can be paused.
+        let (sender, messages) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut input = input;
+            loop {
+                match read_message(&mut input) {
+                    Ok(Some(message)) => {
+                        if sender.send(message).is_err() {
+                            break;
+                        }
+                    }
+                    // The framing held, so the next message can still be read
+                    Err(error) if error.kind() == std::io::ErrorKind::InvalidData => {}
+                    Ok(None) | Err(_) => break,
+                }
+            }
+        });
+
+        while let Ok(message) = messages.recv() {
+            if !self.handle(&message, &messages)? || self.disconnected {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles a request, returning `false` once the editor disconnected.
+    fn handle(&mut self, request: &Value, messages: &Receiver<Value>) -> std::io::Result<bool> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let arguments = &request["arguments"];
+
+        let body = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsReadMemoryRequest": true,
+                "supportsWriteMemoryRequest": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsStepBack": true,
+                "supportsTerminateRequest": true,
+            })),
+            "launch" => self.launch(arguments),
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(Value::Null))?;
+                if command == "terminate" {
+                    self.send_event("terminated", json!({}))?;
+                }
+                return Ok(false);
+            }
+            _ => match self.session.as_mut() {
+                Some(session) => session.handle(command, arguments),
+                None => Err("No program launched".to_string()),
+            },
+        };
+        self.respond(request, body)?;
+
+        match command {
+            "launch" if self.session.is_some() => self.send_event("initialized", json!({}))?,
+            "configurationDone" => {
+                let stop_on_entry = self.session.as_ref().is_some_and(|s| s.stop_on_entry);
+                if stop_on_entry {
+                    self.send_stopped("entry", None)?;
+                } else {
+                    self.run(Run::Continue, messages)?;
+                }
+            }
+            "continue" => self.run(Run::Continue, messages)?,
+            "next" => {
+                let run = self.session.as_ref().map_or(Run::Step, Session::step_over);
+                self.run(run, messages)?;
+            }
+            "stepIn" => self.run(Run::Step, messages)?,
+            "stepOut" => {
+                let return_address = self.session.as_ref().map(Session::return_address);
+                self.run(Run::Until(return_address.unwrap_or(0)), messages)?;
+            }
+            "stepBack" => self.run(Run::StepBack, messages)?,
+            "reverseContinue" => self.run(Run::ReverseContinue, messages)?,
+            _ => {}
+        }
+
+        Ok(true)
+    }
+
+    fn launch(&mut self, arguments: &Value) -> Result<Value, String> {
+        let programs: Vec<&str> = match &arguments["program"] {
+            Value::String(program) => vec![program],
+            Value::Array(programs) => programs.iter().filter_map(Value::as_str).collect(),
+            _ => return Err("launch needs a program".to_string()),
+        };
+
+        let console = MemoryConsole::new(arguments["input"].as_str().unwrap_or("").as_bytes());
+        let mut vm = Vm::with_console(Box::new(console.clone()));
+        if arguments["os"].as_bool() == Some(true) {
+            vm.load_os();
+        }
+
+        let mut listing = None;
+        for program in &programs {
+            let image = load_image_file(program)
+                .map_err(|error| format!("Could not read {}: {}", program, error))?;
+            vm.load_image(&image)
+                .map_err(|error| format!("Could not load {}: {}", program, error))?;
+
+            if let (None, Some((origin, code))) = (listing, image.split_first()) {
+                listing = Some((*origin, code.len() as u16));
+            }
+        }
+        vm.record_history(HISTORY_CAPACITY);
+
+        self.session = Some(Session {
+            vm,
+            console,
+            breakpoints: BTreeSet::new(),
+            listing,
+            stop_on_entry: arguments["stopOnEntry"].as_bool() == Some(true),
+            terminated: false,
+        });
+
+        Ok(Value::Null)
+    }
+
+    /// Runs the program and reports where it stopped. A `pause` request stops
+    /// it, other requests are answered while it runs.
+    fn run(&mut self, run: Run, messages: &Receiver<Value>) -> std::io::Result<()> {
+        let mut executed: u32 = 0;
+
+        let stop = loop {
+            let Some(session) = self.session.as_mut() else {
+                return Ok(());
+            };
+            if session.terminated {
+                return Ok(());
+            }
+
+            if let Some(stop) = session.advance(run) {
+                break stop;
+            }
+
+            executed = executed.wrapping_add(1);
+            if executed.is_multiple_of(PAUSE_CHECK_INTERVAL) {
+                self.send_output()?;
+
+                match messages.try_recv() {
+                    Ok(request) if request["command"] == "pause" => {
+                        self.respond(&request, Ok(Value::Null))?;
+                        break Stop::Paused;
+                    }
+                    Ok(request)
+                        if RUN_COMMANDS.contains(&request["command"].as_str().unwrap_or("")) =>
+                    {
+                        self.respond(&request, Err("The program is running".to_string()))?;
+                    }
+                    Ok(request) => {
+                        if !self.handle(&request, messages)? {
+                            self.disconnected = true;
+                            return Ok(());
+                        }
+                    }
+                    Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            }
+        };
+        self.send_output()?;
+
+        match stop {
+            Stop::Step => self.send_stopped("step", None),
+            Stop::Breakpoint => self.send_stopped("breakpoint", None),
+            Stop::Paused => self.send_stopped("pause", None),
+            Stop::Fault(description) => self.send_stopped("exception", Some(description)),
+            Stop::Exited => {
+                if let Some(session) = self.session.as_mut() {
+                    session.terminated = true;
+                }
+                self.send_event("exited", json!({ "exitCode": 0 }))?;
+                self.send_event("terminated", json!({}))
+            }
+        }
+    }
+
+    fn send_output(&mut self) -> std::io::Result<()> {
+        let Some(session) = self.session.as_ref() else {
+            return Ok(());
+        };
+        let output = session.console.take_output();
+
+        if output.is_empty() {
+            Ok(())
+        } else {
+            let output = String::from_utf8_lossy(&output).into_owned();
+            self.send_event("output", json!({ "category": "stdout", "output": output }))
+        }
+    }
+
+    fn send_stopped(&mut self, reason: &str, description: Option<String>) -> std::io::Result<()> {
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(description) = description {
+            body["description"] = json!(description);
+            body["text"] = json!(description);
+        }
+
+        self.send_event("stopped", body)
+    }
+
+    fn respond(&mut self, request: &Value, body: Result<Value, String>) -> std::io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": body.is_ok(),
+        });
+        match body {
+            Ok(Value::Null) => {}
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = Value::String(message),
+        }
+
+        self.send(response)
+    }
+
+    fn send_event(&mut self, event: &str, body: Value) -> std::io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn send(&mut self, mut message: Value) -> std::io::Result<()> {
+        self.sequence += 1;
+        message["seq"] = json!(self.sequence);
+        let message = message.to_string();
+
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            message.len(),
+            message
+        )?;
+        self.output.flush()
+    }
+}
+
+/// Why running stopped.
+#[derive(Debug)]
+enum Stop {
+    Step,
+    Breakpoint,
+    Paused,
+    Fault(String),
+    Exited,
+}
+
+impl Session {
+    /// Handles the requests that only inspect or configure the program.
+    fn handle(&mut self, command: &str, arguments: &Value) -> Result<Value, String> {
+        match command {
+            _ if RUN_COMMANDS.contains(&command) => Ok(json!({ "allThreadsContinued": true })),
+            "pause" => Ok(Value::Null),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "LC-3" }] })),
+            "setBreakpoints" => Ok(self.set_line_breakpoints(arguments)),
+            "setInstructionBreakpoints" => Ok(self.set_instruction_breakpoints(arguments)),
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                { "name": "Condition codes", "variablesReference": CONDITION_CODES_REFERENCE, "expensive": false },
+            ] })),
+            "variables" => Ok(self.variables(arguments["variablesReference"].as_u64())),
+            "source" => self.source(),
+            "readMemory" => self.read_memory(arguments),
+            "writeMemory" => self.write_memory(arguments),
+            _ => Err(format!("Unsupported request {}", command)),
+        }
+    }
+
+    /// How to step over the instruction at the PC: JSR, JSRR and TRAP run
+    /// until they return to the next instruction, anything else is stepped.
+    fn step_over(&self) -> Run {
+        let pc = self.vm.state().pc;
+
+        match self.vm.state().memory[pc as usize] >> 12 {
+            0x4 | 0xF => Run::Until(pc.wrapping_add(1)),
+            _ => Run::Step,
+        }
+    }
+
+    /// Where the innermost subroutine or handler returns to, or R7 outside of
+    /// any.
+    fn return_address(&self) -> u16 {
+        match self.vm.call_stack().frames().last() {
+            Some(frame) => frame.return_address,
+            None => self.vm.state().registers[7],
+        }
+    }
+
+    /// Executes (or undoes) one instruction, returning why running has to
+    /// stop, if it has.
+    fn advance(&mut self, run: Run) -> Option<Stop> {
+        if matches!(run, Run::StepBack | Run::ReverseContinue) {
+            if !self.vm.step_back() {
+                return Some(Stop::Step);
+            }
+            return match run {
+                Run::StepBack => Some(Stop::Step),
+                _ if self.breakpoints.contains(&self.vm.state().pc) => Some(Stop::Breakpoint),
+                _ => None,
+            };
+        }
+
+        loop {
+            match self.vm.step() {
+                StepOutcome::Executed
+                | StepOutcome::Trapped(_)
+                | StepOutcome::Exception(_)
+                | StepOutcome::Interrupted(_) => break,
+                StepOutcome::WaitingForInput => std::thread::sleep(INPUT_POLL_INTERVAL),
+                StepOutcome::Halted | StepOutcome::EndOfInput => return Some(Stop::Exited),
+                StepOutcome::Faulted(error) => return Some(Stop::Fault(error.to_string())),
+            }
+        }
+
+        let pc = self.vm.state().pc;
+        match run {
+            Run::Step => Some(Stop::Step),
+            Run::Until(address) if pc == address => Some(Stop::Step),
+            _ if self.breakpoints.contains(&pc) => Some(Stop::Breakpoint),
+            _ => None,
+        }
+    }
+
+    fn set_line_breakpoints(&mut self, arguments: &Value) -> Value {
+        let lines: Vec<u64> = arguments["breakpoints"]
+            .as_array()
+            .map(|breakpoints| {
+                breakpoints
+                    .iter()
+                    .filter_map(|breakpoint| breakpoint["line"].as_u64())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // Breakpoints on the listing replace the ones set on it before
+        if let Some((origin, len)) = self.listing {
+            let listed = origin..origin.saturating_add(len);
+            self.breakpoints.retain(|address| !listed.contains(address));
+        }
+
+        let breakpoints: Vec<Value> = lines
+            .iter()
+            .map(|line| match self.line_address(*line) {
+                Some(address) => {
+                    self.breakpoints.insert(address);
+                    json!({ "verified": true, "line": line, "instructionReference": format!("0x{:04X}", address) })
+                }
+                None => json!({ "verified": false, "line": line, "message": "Not an address of the program" }),
+            })
+            .collect();
+
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn set_instruction_breakpoints(&mut self, arguments: &Value) -> Value {
+        let breakpoints: Vec<Value> = arguments["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|breakpoint| {
+                let reference = breakpoint["instructionReference"].as_str().unwrap_or("");
+                let offset = breakpoint["offset"].as_i64().unwrap_or(0);
+
+                match parse_address(reference) {
+                    Some(address) => {
+                        let address = (address as i64 + offset) as u16;
+                        self.breakpoints.insert(address);
+                        json!({ "verified": true, "instructionReference": format!("0x{:04X}", address) })
+                    }
+                    None => json!({ "verified": false, "message": "Invalid address" }),
+                }
+            })
+            .collect();
+
+        json!({ "breakpoints": breakpoints })
+    }
+
+    /// Frames come from the VM's call stack: the innermost one is at the PC
+    /// and each of its callers where the frame it called returns to.
+    fn stack_trace(&self) -> Value {
+        let state = self.vm.state();
+        let frames: Vec<u16> = std::iter::once(state.pc)
+            .chain(
+                self.vm
+                    .call_stack()
+                    .frames()
+                    .iter()
+                    .rev()
+                    .map(|frame| frame.return_address),
+            )
+            .take(MAX_FRAMES)
+            .collect();
+
+        let frames: Vec<Value> = frames
+            .iter()
+            .enumerate()
+            .map(|(id, address)| {
+                let instruction = state.memory[*address as usize];
+                let mut frame = json!({
+                    "id": id,
+                    "name": format!("0x{:04X}: {}", address, disassemble_instruction(instruction, *address)),
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("0x{:04X}", address),
+                });
+                if let Some(line) = self.address_line(*address) {
+                    frame["source"] = self.listing_source();
+                    frame["line"] = json!(line);
+                }
+                frame
+            })
+            .collect();
+
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+
+    fn variables(&self, reference: Option<u64>) -> Value {
+        let state = self.vm.state();
+        let variable = |name: &str, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+
+        let variables: Vec<Value> = match reference {
+            Some(REGISTERS_REFERENCE) => {
+                let mut variables: Vec<Value> = state
+                    .registers
+                    .iter()
+                    .enumerate()
+                    .map(|(index, value)| {
+                        let mut register =
+                            variable(&format!("R{}", index), format!("0x{:04X}", value));
+                        register["memoryReference"] = json!(format!("0x{:04X}", value));
+                        register
+                    })
+                    .collect();
+                variables.push(variable("PC", format!("0x{:04X}", state.pc)));
+                variables.push(variable("PSR", format!("0x{:04X}", state.psr)));
+                variables
+            }
+            Some(CONDITION_CODES_REFERENCE) => {
+                let codes = state.condition_codes();
+                let privilege = match state.privilege() {
+                    Privilege::Supervisor => "supervisor",
+                    Privilege::User => "user",
+                };
+                vec![
+                    variable("N", ((codes >> 2) & 1).to_string()),
+                    variable("Z", ((codes >> 1) & 1).to_string()),
+                    variable("P", (codes & 1).to_string()),
+                    variable("Privilege", privilege.to_string()),
+                    variable("Priority", state.priority().to_string()),
+                ]
+            }
+            _ => Vec::new(),
+        };
+
+        json!({ "variables": variables })
+    }
+
+    fn source(&self) -> Result<Value, String> {
+        let (origin, len) = self.listing.ok_or("No program listing")?;
+        let memory = &self.vm.state().memory;
+
+        let content: String = (origin..origin.saturating_add(len))
+            .map(|address| {
+                let instruction = memory[address as usize];
+                format!(
+                    "0x{:04X}  {:04X}  {}\n",
+                    address,
+                    instruction,
+                    disassemble_instruction(instruction, address)
+                )
+            })
+            .collect();
+
+        Ok(json!({ "content": content, "mimeType": "text/x-lc3-disassembly" }))
+    }
+
+    /// Reads memory as stored, without the side effects reading the device
+    /// registers would have.
+    fn read_memory(&self, arguments: &Value) -> Result<Value, String> {
+        let reference = arguments["memoryReference"].as_str().unwrap_or("");
+        let address = parse_address(reference).ok_or("Invalid memory reference")?;
+        let offset = arguments["offset"].as_i64().unwrap_or(0);
+        let count = arguments["count"].as_u64().unwrap_or(0) as usize;
+        let start = (address as i64 + offset / 2) as u16;
+
+        let memory = &self.vm.state().memory;
+        let mut bytes: Vec<u8> = (0..count.div_ceil(2))
+            .flat_map(|index| memory[start.wrapping_add(index as u16) as usize].to_be_bytes())
+            .collect();
+        bytes.truncate(count);
+
+        Ok(json!({ "address": format!("0x{:04X}", start), "data": base64(&bytes) }))
+    }
+
+    /// Writes memory the way the debugger's `set` does, which forgets the
+    /// steps recorded so far. A lone byte at the end only replaces the most
+    /// significant half of its word.
+    fn write_memory(&mut self, arguments: &Value) -> Result<Value, String> {
+        let reference = arguments["memoryReference"].as_str().unwrap_or("");
+        let address = parse_address(reference).ok_or("Invalid memory reference")?;
+        let offset = arguments["offset"].as_i64().unwrap_or(0);
+        let data = arguments["data"].as_str().unwrap_or("");
+        let bytes = base64_decode(data).ok_or("Invalid base64 data")?;
+        let start = (address as i64 + offset / 2) as u16;
+
+        let memory = &self.vm.state().memory;
+        let words: Vec<u16> = bytes
+            .chunks(2)
+            .enumerate()
+            .map(|(index, pair)| match pair {
+                [high, low] => u16::from_be_bytes([*high, *low]),
+                _ => {
+                    let old = memory[start.wrapping_add(index as u16) as usize];
+                    (old & 0x00FF) | ((pair[0] as u16) << 8)
+                }
+            })
+            .collect();
+        self.vm.write_memory(start, &words);
+
+        Ok(json!({ "bytesWritten": bytes.len() }))
+    }
+
+    fn listing_source(&self) -> Value {
+        json!({ "name": "program.lst", "sourceReference": LISTING_REFERENCE })
+    }
+
+    fn line_address(&self, line: u64) -> Option<u16> {
+        let (origin, len) = self.listing?;
+
+        (1..=len as u64)
+            .contains(&line)
+            .then(|| origin.wrapping_add(line as u16 - 1))
+    }
+
+    fn address_line(&self, address: u16) -> Option<u64> {
+        let (origin, len) = self.listing?;
+
+        (origin..origin.saturating_add(len))
+            .contains(&address)
+            .then(|| (address - origin) as u64 + 1)
+    }
+}
+
+/// Parses `0x3000` or `12288`.
+fn parse_address(reference: &str) -> Option<u16> {
+    match reference.strip_prefix("0x") {
+        Some(digits) => u16::from_str_radix(digits, 16).ok(),
+        None => reference.parse().ok(),
+    }
+}
+
+/// Reads a message framed by a `Content-Length` header. Returns `None` at the
+/// end of the input, and an [`InvalidData`](std::io::ErrorKind::InvalidData)
+/// error when the message is not valid JSON.
+fn read_message(input: &mut impl BufRead) -> std::io::Result<Option<Value>> {
+    let mut length = None;
+
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+
+        let header = header.trim();
+        if header.is_empty() {
+            if length.is_some() {
+                break;
+            }
+        } else if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let mut body = vec![0; length.unwrap_or(0)];
+    input.read_exact(&mut body)?;
+
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (index, byte)| {
+            group | (*byte as u32) << (16 - 8 * index)
+        });
+
+        for index in 0..4 {
+            if index <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(group >> (18 - 6 * index)) as usize & 0x3F] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+/// Decodes padded base64, or returns `None` if `text` isn't base64.
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut decoded = Vec::new();
+
+    for chunk in text.chunks(4) {
+        let padding = chunk.iter().rev().take_while(|&&byte| byte == b'=').count();
+        if padding > 2 {
+            return None;
+        }
+        let mut group = 0u32;
+        for (index, byte) in chunk[..4 - padding].iter().enumerate() {
+            let value = BASE64_ALPHABET.iter().position(|letter| letter == byte)?;
+            group |= (value as u32) << (18 - 6 * index);
+        }
+
+        decoded.extend(&group.to_be_bytes()[1..4 - padding.min(2)]);
+    }
+
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A session on `program`, loaded at 0x3000 and listed as its source.
+    fn session(program: &[u16]) -> Session {
+        let console = MemoryConsole::new(&[]);
+        let mut vm = Vm::with_console(Box::new(console.clone()));
+        let mut image = vec![0x3000];
+        image.extend(program);
+        vm.load_image(&image).unwrap();
+        vm.record_history(HISTORY_CAPACITY);
+
+        Session {
+            vm,
+            console,
+            breakpoints: BTreeSet::new(),
+            listing: Some((0x3000, program.len() as u16)),
+            stop_on_entry: false,
+            terminated: false,
+        }
+    }
+
+    #[test]
+    fn messages_are_framed_by_their_content_length() {
+        let mut server = DapServer::new(Vec::new());
+        server
+            .send_event("output", json!({ "output": "héllo" }))
+            .unwrap();
+        server.send_event("terminated", json!({})).unwrap();
+
+        let mut input = server.output.as_slice();
+        let first = read_message(&mut input).unwrap().unwrap();
+        let second = read_message(&mut input).unwrap().unwrap();
+
+        assert_eq!(first["body"]["output"], "héllo");
+        assert_eq!(first["seq"], 1);
+        assert_eq!(second["event"], "terminated");
+        assert_eq!(second["seq"], 2);
+        assert!(read_message(&mut input).unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_messages_are_errors() {
+        let mut input = "Content-Length: 6\r\n\r\n{oops}".as_bytes();
+
+        let error = read_message(&mut input).unwrap_err();
+
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn breakpoints_on_listing_lines_map_to_addresses() {
+        let mut session = session(&[0x1021, 0x1021, 0xF025]);
+
+        let body = session
+            .handle(
+                "setBreakpoints",
+                &json!({ "breakpoints": [{ "line": 2 }, { "line": 4 }] }),
+            )
+            .unwrap();
+
+        assert_eq!(body["breakpoints"][0]["verified"], true);
+        assert_eq!(body["breakpoints"][0]["instructionReference"], "0x3001");
+        assert_eq!(body["breakpoints"][1]["verified"], false);
+        assert_eq!(session.breakpoints, BTreeSet::from([0x3001]));
+
+        // Setting them again replaces them
+        session
+            .handle("setBreakpoints", &json!({ "breakpoints": [{ "line": 3 }] }))
+            .unwrap();
+        assert_eq!(session.breakpoints, BTreeSet::from([0x3002]));
+    }
+
+    #[test]
+    fn stack_traces_follow_the_call_stack() {
+        let mut session = session(&[
+            0x4801, // JSR SUBROUTINE
+            0xF025, // HALT
+            0x1020, // SUBROUTINE: ADD R0, R0, #0
+            0xC1C0, // RET
+        ]);
+        session.advance(Run::Step);
+        session.advance(Run::Step);
+
+        let trace = session.handle("stackTrace", &json!({})).unwrap();
+        let frames = trace["stackFrames"].as_array().unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0]["instructionPointerReference"], "0x3003");
+        assert_eq!(frames[0]["line"], 4);
+        assert_eq!(frames[1]["instructionPointerReference"], "0x3001");
+        assert_eq!(session.return_address(), 0x3001);
+    }
+
+    #[test]
+    fn memory_is_read_and_written_most_significant_byte_first() {
+        let mut session = session(&[0x1234, 0xABCD]);
+
+        let read = session
+            .handle(
+                "readMemory",
+                &json!({ "memoryReference": "0x3000", "count": 4 }),
+            )
+            .unwrap();
+        assert_eq!(read["address"], "0x3000");
+        assert_eq!(read["data"], base64(&[0x12, 0x34, 0xAB, 0xCD]));
+
+        let written = session
+            .handle(
+                "writeMemory",
+                &json!({ "memoryReference": "0x3000", "offset": 2, "data": base64(&[0xBE, 0xEF, 0x56]) }),
+            )
+            .unwrap();
+        assert_eq!(written["bytesWritten"], 3);
+        assert_eq!(session.vm.state().memory[0x3001], 0xBEEF);
+        assert_eq!(session.vm.state().memory[0x3002], 0x5600);
+    }
+
+    #[test]
+    fn base64_round_trips() {
+        for bytes in [&b""[..], b"a", b"ab", b"abc", b"abcd"] {
+            assert_eq!(base64_decode(&base64(bytes)).unwrap(), bytes);
+        }
+        assert_eq!(base64(&[0x12, 0x34, 0xAB, 0xCD]), "EjSrzQ==");
+        assert!(base64_decode("abc").is_none());
+        assert!(base64_decode("a===").is_none());
+    }
+}
@@ -0,0 +1,647 @@
+//! An interactive debugger built on [`Vm::step`].
+//!
+//! Each line typed at the prompt is parsed into a [`Command`] and executed by
+//! the [`Debugger`], which returns the text to show for it. Type `help` for the
+//! list of commands.
+
+use super::disassembler::disassemble_instruction;
+use super::state::Privilege;
+use super::vm::{StepOutcome, Vm, INPUT_POLL_INTERVAL};
+use super::watchpoint::{Access, Condition, Watchpoint};
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// How many instructions `list` shows by default.
+const LIST_LENGTH: u16 = 10;
+/// How many words `mem` shows by default.
+const DUMP_LENGTH: u16 = 8;
+/// How many steps are recorded for reverse execution.
+const HISTORY_CAPACITY: usize = 1 << 20;
+/// How many instructions `continue` runs between checks for a pause.
+const PAUSE_CHECK_INTERVAL: u32 = 1024;
+
+const HELP: &str = "\
+step [count]          execute one or more instructions (s)
+continue              run until a breakpoint or the program stops (c)
+reverse-step [count]  undo one or more instructions (rs)
+reverse-continue      run backwards to the previous breakpoint (rc)
+last-writer <address> find the instruction that last wrote an address (lw)
+break <address>       set a breakpoint (b)
+clear <address>       remove a breakpoint
+breakpoints           list the breakpoints
+watch <start> [end] [read|write|access] [changed|equals <value>]
+                      stop when the program writes (or reads) memory (w)
+unwatch <number>      remove a watchpoint
+watchpoints           list the watchpoints
+registers             print the registers and condition codes (r)
+backtrace             list the subroutines and handlers being executed (bt)
+mem <address> [count] dump memory (x)
+set <address> <value> ...
+                      write consecutive words into memory, which
+                      forgets the steps recorded so far
+list [address] [count]
+                      disassemble around the PC or from an address (l)
+help                  print this message (h)
+quit                  leave the debugger (q)
+Numbers are decimal, or hexadecimal when prefixed with x or 0x.";
+
+/// A debugger command, as typed at the prompt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Step(u16),
+    Continue,
+    ReverseStep(u16),
+    ReverseContinue,
+    LastWriter(u16),
+    Break(u16),
+    Clear(u16),
+    Breakpoints,
+    Watch(Watchpoint),
+    Unwatch(usize),
+    Watchpoints,
+    Registers,
+    Backtrace,
+    Memory { start: u16, count: u16 },
+    Set { start: u16, values: Vec<u16> },
+    List { start: Option<u16>, count: u16 },
+    Help,
+    Quit,
+}
+
+impl Command {
+    /// Parses a line typed at the prompt, or explains what is wrong with it.
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or("");
+        if name == "w" || name == "watch" {
+            return parse_watch(&words.collect::<Vec<_>>());
+        }
+        let arguments = words.map(parse_number).collect::<Result<Vec<_>, _>>()?;
+
+        let command = match (name, arguments.as_slice()) {
+            ("s" | "step", []) => Command::Step(1),
+            ("s" | "step", [count]) => Command::Step(*count),
+            ("c" | "continue", []) => Command::Continue,
+            ("rs" | "reverse-step", []) => Command::ReverseStep(1),
+            ("rs" | "reverse-step", [count]) => Command::ReverseStep(*count),
+            ("rc" | "reverse-continue", []) => Command::ReverseContinue,
+            ("lw" | "last-writer", [address]) => Command::LastWriter(*address),
+            ("b" | "break", [address]) => Command::Break(*address),
+            ("clear", [address]) => Command::Clear(*address),
+            ("breakpoints", []) => Command::Breakpoints,
+            ("unwatch", [number]) => Command::Unwatch(*number as usize),
+            ("watchpoints", []) => Command::Watchpoints,
+            ("r" | "registers", []) => Command::Registers,
+            ("bt" | "backtrace", []) => Command::Backtrace,
+            ("x" | "mem", [start]) => Command::Memory {
+                start: *start,
+                count: DUMP_LENGTH,
+            },
+            ("x" | "mem", [start, count]) => Command::Memory {
+                start: *start,
+                count: *count,
+            },
+            ("set", [start, values @ ..]) if !values.is_empty() => Command::Set {
+                start: *start,
+                values: values.to_vec(),
+            },
+            ("l" | "list", []) => Command::List {
+                start: None,
+                count: LIST_LENGTH,
+            },
+            ("l" | "list", [start]) => Command::List {
+                start: Some(*start),
+                count: LIST_LENGTH,
+            },
+            ("l" | "list", [start, count]) => Command::List {
+                start: Some(*start),
+                count: *count,
+            },
+            ("h" | "help", []) => Command::Help,
+            ("q" | "quit", []) => Command::Quit,
+            ("", _) => return Err("Type a command, or help".to_string()),
+            _ => return Err(format!("Invalid command: {}, type help", line.trim())),
+        };
+
+        Ok(command)
+    }
+}
+
+/// Parses the arguments of `watch`: the addresses, which accesses to watch,
+/// writes by default, and the condition.
+fn parse_watch(words: &[&str]) -> Result<Command, String> {
+    let addresses: Vec<u16> = words
+        .iter()
+        .map_while(|word| parse_number(word).ok())
+        .collect();
+    let (start, end) = match addresses.as_slice() {
+        [address] => (*address, *address),
+        [start, end] if start <= end => (*start, *end),
+        _ => return Err("watch needs an address or a range of addresses".to_string()),
+    };
+
+    let mut watchpoint = Watchpoint {
+        addresses: start..=end,
+        read: false,
+        write: true,
+        condition: Condition::Always,
+    };
+    let mut options = words[addresses.len()..].iter();
+    while let Some(option) = options.next() {
+        match *option {
+            "read" => (watchpoint.read, watchpoint.write) = (true, false),
+            "write" => (watchpoint.read, watchpoint.write) = (false, true),
+            "access" => (watchpoint.read, watchpoint.write) = (true, true),
+            "changed" => watchpoint.condition = Condition::Changed,
+            "equals" => {
+                let value = options.next().ok_or("equals needs a value")?;
+                watchpoint.condition = Condition::Equals(parse_number(value)?);
+            }
+            _ => return Err(format!("Invalid watch option: {}", option)),
+        }
+    }
+
+    Ok(Command::Watch(watchpoint))
+}
+
+/// Parses `x3000`, `0x3000` or `12288`.
+fn parse_number(word: &str) -> Result<u16, String> {
+    let hexadecimal = word
+        .strip_prefix("0x")
+        .or_else(|| word.strip_prefix('x'))
+        .or_else(|| word.strip_prefix('X'));
+
+    match hexadecimal {
+        Some(digits) => u16::from_str_radix(digits, 16),
+        None => word.parse(),
+    }
+    .map_err(|_| format!("Invalid number: {}", word))
+}
+
+/// Drives a [`Vm`] one command at a time.
+#[derive(Debug)]
+pub struct Debugger {
+    vm: Vm,
+    breakpoints: BTreeSet<u16>,
+    /// Set to stop the program while it runs, see [`Debugger::pause_flag`]
+    pause: Arc<AtomicBool>,
+}
+
+impl Debugger {
+    /// Takes over `vm`, recording its steps so they can be undone.
+    pub fn new(mut vm: Vm) -> Debugger {
+        vm.record_history(HISTORY_CAPACITY);
+
+        Debugger {
+            vm,
+            breakpoints: BTreeSet::new(),
+            pause: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// A flag that stops the program as soon as possible when set while a
+    /// `step` or `continue` runs, e.g. from a SIGINT handler. Setting it while
+    /// no command runs has no effect.
+    pub fn pause_flag(&self) -> Arc<AtomicBool> {
+        self.pause.clone()
+    }
+
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut Vm {
+        &mut self.vm
+    }
+
+    /// Executes `command` and returns what to show for it. [`Command::Quit`]
+    /// is left to the caller and does nothing here.
+    pub fn execute(&mut self, command: &Command) -> String {
+        match command {
+            Command::Step(count) => self.step(*count),
+            Command::Continue => self.resume(),
+            Command::ReverseStep(count) => self.reverse_step(*count),
+            Command::ReverseContinue => self.reverse_resume(),
+            Command::LastWriter(address) => self.last_writer(*address),
+            Command::Break(address) => {
+                self.breakpoints.insert(*address);
+                format!("Breakpoint set at 0x{:04X}", address)
+            }
+            Command::Clear(address) => {
+                if self.breakpoints.remove(address) {
+                    format!("Breakpoint at 0x{:04X} cleared", address)
+                } else {
+                    format!("No breakpoint at 0x{:04X}", address)
+                }
+            }
+            Command::Breakpoints => self.list_breakpoints(),
+            Command::Watch(watchpoint) => {
+                let watchpoints = &mut self.vm.state_mut().watchpoints;
+                watchpoints.push(watchpoint.clone());
+                format!(
+                    "Watchpoint {}: {}",
+                    watchpoints.len(),
+                    describe_watchpoint(watchpoint)
+                )
+            }
+            Command::Unwatch(number) => {
+                let watchpoints = &mut self.vm.state_mut().watchpoints;
+                if (1..=watchpoints.len()).contains(number) {
+                    watchpoints.remove(number - 1);
+                    format!("Watchpoint {} removed", number)
+                } else {
+                    format!("No watchpoint {}", number)
+                }
+            }
+            Command::Watchpoints => self.list_watchpoints(),
+            Command::Registers => self.registers(),
+            Command::Backtrace => self.backtrace(),
+            Command::Memory { start, count } => self.dump(*start, *count),
+            Command::Set { start, values } => {
+                self.vm.write_memory(*start, values);
+                format!("Wrote {} words at 0x{:04X}", values.len(), start)
+            }
+            Command::List { start, count } => {
+                let start = start.unwrap_or_else(|| self.vm.state().pc.saturating_sub(count / 2));
+                self.list(start, *count)
+            }
+            Command::Help => HELP.to_string(),
+            Command::Quit => String::new(),
+        }
+    }
+
+    /// Executes `count` instructions, stopping early if the program stops.
+    fn step(&mut self, count: u16) -> String {
+        self.pause.store(false, Ordering::Relaxed);
+
+        for _ in 0..count {
+            if let Some(stop) = self.step_once() {
+                return stop;
+            }
+        }
+
+        self.current_line()
+    }
+
+    /// Runs until reaching a breakpoint, the program stops or it is paused.
+    /// The instruction at the PC always runs, even if it has a breakpoint.
+    fn resume(&mut self) -> String {
+        self.pause.store(false, Ordering::Relaxed);
+        let mut executed: u32 = 0;
+
+        loop {
+            if let Some(stop) = self.step_once() {
+                return stop;
+            }
+
+            let pc = self.vm.state().pc;
+            if self.breakpoints.contains(&pc) {
+                return format!("Breakpoint at 0x{:04X}\n{}", pc, self.current_line());
+            }
+
+            executed = executed.wrapping_add(1);
+            if executed.is_multiple_of(PAUSE_CHECK_INTERVAL) && self.pause.load(Ordering::Relaxed) {
+                return self.paused();
+            }
+        }
+    }
+
+    fn paused(&self) -> String {
+        format!(
+            "Paused at 0x{:04X}\n{}",
+            self.vm.state().pc,
+            self.current_line()
+        )
+    }
+
+    /// Undoes `count` instructions, stopping early at the start of the
+    /// recorded history.
+    fn reverse_step(&mut self, count: u16) -> String {
+        for _ in 0..count {
+            if !self.vm.step_back() {
+                return format!("Reached the start of the history\n{}", self.current_line());
+            }
+        }
+
+        self.current_line()
+    }
+
+    /// Runs backwards until reaching a breakpoint or the start of the recorded
+    /// history. The instruction before the PC is always undone, even if it has
+    /// a breakpoint.
+    fn reverse_resume(&mut self) -> String {
+        loop {
+            if !self.vm.step_back() {
+                return format!("Reached the start of the history\n{}", self.current_line());
+            }
+
+            let pc = self.vm.state().pc;
+            if self.breakpoints.contains(&pc) {
+                return format!("Breakpoint at 0x{:04X}\n{}", pc, self.current_line());
+            }
+        }
+    }
+
+    fn last_writer(&self, address: u16) -> String {
+        let history = self.vm.history().expect("the debugger records the history");
+
+        match history.last_writer(address) {
+            Some((steps, delta, write)) => format!(
+                "Written {} step{} ago with 0x{:04X} (was 0x{:04X}) by\n{}",
+                steps,
+                if steps == 1 { "" } else { "s" },
+                write.value,
+                write.old,
+                self.line(delta.pc)
+            ),
+            None => format!("0x{:04X} was not written in the recorded history", address),
+        }
+    }
+
+    /// Steps the VM, waiting for input when the program needs a key unless it
+    /// is paused, and describes why it stopped if it did.
+    fn step_once(&mut self) -> Option<String> {
+        let pc = self.vm.state().pc;
+
+        loop {
+            match self.vm.step() {
+                StepOutcome::Executed
+                | StepOutcome::Trapped(_)
+                | StepOutcome::Exception(_)
+                | StepOutcome::Interrupted(_) => return self.watch_hit(pc),
+                StepOutcome::WaitingForInput if self.pause.load(Ordering::Relaxed) => {
+                    return Some(self.paused())
+                }
+                StepOutcome::WaitingForInput => std::thread::sleep(INPUT_POLL_INTERVAL),
+                StepOutcome::Halted => return Some("Program halted".to_string()),
+                StepOutcome::EndOfInput => return Some("Program ran out of input".to_string()),
+                StepOutcome::Faulted(error) => return Some(error.to_string()),
+            }
+        }
+    }
+
+    /// Describes the first access the instruction at `pc` made that matched a
+    /// watchpoint, if any.
+    fn watch_hit(&mut self, pc: u16) -> Option<String> {
+        let hits = std::mem::take(&mut self.vm.state_mut().watch_hits);
+        let hit = hits.first()?;
+
+        let access = match hit.access {
+            Access::Read => format!("read 0x{:04X} from 0x{:04X}", hit.value, hit.address),
+            Access::Write => format!(
+                "wrote 0x{:04X} to 0x{:04X} (was 0x{:04X})",
+                hit.value, hit.address, hit.old
+            ),
+        };
+        Some(format!(
+            "Watchpoint {}: 0x{:04X} {}\n{}",
+            hit.watchpoint + 1,
+            pc,
+            access,
+            self.current_line()
+        ))
+    }
+
+    fn list_watchpoints(&self) -> String {
+        let watchpoints = &self.vm.state().watchpoints;
+        if watchpoints.is_empty() {
+            return "No watchpoints".to_string();
+        }
+
+        watchpoints
+            .iter()
+            .enumerate()
+            .map(|(index, watchpoint)| {
+                format!("{}: {}", index + 1, describe_watchpoint(watchpoint))
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn list_breakpoints(&self) -> String {
+        if self.breakpoints.is_empty() {
+            return "No breakpoints".to_string();
+        }
+
+        self.breakpoints
+            .iter()
+            .map(|address| self.line(*address))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Lists where each frame of the call stack is, innermost first: the PC
+    /// for the innermost one, and where the callee returns to for the others.
+    fn backtrace(&self) -> String {
+        let frames = self.vm.call_stack().frames();
+        let mut address = self.vm.state().pc;
+        let mut report = String::new();
+
+        for (depth, frame) in frames.iter().rev().enumerate() {
+            let kind = if frame.handler {
+                "handler"
+            } else {
+                "subroutine"
+            };
+            let _ = writeln!(
+                report,
+                "#{:<2} 0x{:04X} in {} 0x{:04X}",
+                depth, address, kind, frame.entry
+            );
+            address = frame.return_address;
+        }
+        let _ = write!(
+            report,
+            "#{:<2} 0x{:04X} in the program",
+            frames.len(),
+            address
+        );
+
+        report
+    }
+
+    fn registers(&self) -> String {
+        let state = self.vm.state();
+        let mut report = String::new();
+
+        for (index, value) in state.registers.iter().enumerate() {
+            let separator = if index % 4 == 3 { "\n" } else { "  " };
+            let _ = write!(report, "R{} 0x{:04X}{}", index, value, separator);
+        }
+
+        let codes = state.condition_codes();
+        let flags: String = [(4, 'N'), (2, 'Z'), (1, 'P')]
+            .iter()
+            .filter(|(bit, _)| codes & bit != 0)
+            .map(|(_, flag)| *flag)
+            .collect();
+        let privilege = match state.privilege() {
+            Privilege::Supervisor => "supervisor",
+            Privilege::User => "user",
+        };
+        let _ = write!(
+            report,
+            "PC 0x{:04X}  PSR 0x{:04X} ({}, priority {})  CC {}",
+            state.pc,
+            state.psr,
+            privilege,
+            state.priority(),
+            if flags.is_empty() { "-" } else { &flags }
+        );
+
+        report
+    }
+
+    /// Shows memory as it is stored, without the side effects reading the
+    /// device registers would have.
+    fn dump(&self, start: u16, count: u16) -> String {
+        let memory = &self.vm.state().memory;
+
+        (start..=u16::MAX)
+            .take(count as usize)
+            .collect::<Vec<_>>()
+            .chunks(8)
+            .map(|row| {
+                let words: Vec<String> = row
+                    .iter()
+                    .map(|address| format!("{:04X}", memory[*address as usize]))
+                    .collect();
+                format!("0x{:04X}: {}", row[0], words.join(" "))
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn list(&self, start: u16, count: u16) -> String {
+        (start..=u16::MAX)
+            .take(count as usize)
+            .map(|address| self.line(address))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn current_line(&self) -> String {
+        self.line(self.vm.state().pc)
+    }
+
+    /// A disassembly line, marking the PC with `=>` and breakpoints with `*`.
+    fn line(&self, address: u16) -> String {
+        let instruction = self.vm.state().memory[address as usize];
+        let pc = if address == self.vm.state().pc {
+            "=>"
+        } else {
+            "  "
+        };
+        let breakpoint = if self.breakpoints.contains(&address) {
+            "*"
+        } else {
+            " "
+        };
+
+        format!(
+            "{}{} 0x{:04X}  {:04X}  {}",
+            pc,
+            breakpoint,
+            address,
+            instruction,
+            disassemble_instruction(instruction, address)
+        )
+    }
+}
+
+/// E.g. `writes to 0x4000..=0x4003 changing the value`.
+fn describe_watchpoint(watchpoint: &Watchpoint) -> String {
+    let access = match (watchpoint.read, watchpoint.write) {
+        (true, true) => "accesses to",
+        (true, false) => "reads from",
+        _ => "writes to",
+    };
+    let (start, end) = (watchpoint.addresses.start(), watchpoint.addresses.end());
+    let addresses = if start == end {
+        format!("0x{:04X}", start)
+    } else {
+        format!("0x{:04X}..=0x{:04X}", start, end)
+    };
+    let condition = match watchpoint.condition {
+        Condition::Always => String::new(),
+        Condition::Changed => " changing the value".to_string(),
+        Condition::Equals(value) => format!(" of 0x{:04X}", value),
+    };
+
+    format!("{} {}{}", access, addresses, condition)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::MemoryConsole;
+
+    fn watch(line: &str) -> Watchpoint {
+        match Command::parse(line) {
+            Ok(Command::Watch(watchpoint)) => watchpoint,
+            other => panic!("{} parsed as {:?}", line, other),
+        }
+    }
+
+    #[test]
+    fn backtrace_lists_the_call_stack_innermost_first() {
+        let mut vm = Vm::with_console(Box::new(MemoryConsole::new(&[])));
+        vm.load_image(&[
+            0x3000, // origin
+            0x4801, // JSR SUBROUTINE
+            0xF025, // HALT
+            0x1020, // SUBROUTINE: ADD R0, R0, #0
+            0xC1C0, // RET
+        ])
+        .unwrap();
+        let mut debugger = Debugger::new(vm);
+
+        debugger.execute(&Command::Step(2));
+        assert_eq!(
+            debugger.execute(&Command::parse("bt").unwrap()),
+            "#0  0x3003 in subroutine 0x3002\n#1  0x3001 in the program"
+        );
+
+        debugger.execute(&Command::Step(1));
+        assert_eq!(
+            debugger.execute(&Command::Backtrace),
+            "#0  0x3001 in the program"
+        );
+    }
+
+    #[test]
+    fn watch_defaults_to_writes_of_a_single_address() {
+        let watchpoint = watch("watch x3100");
+
+        assert_eq!(watchpoint.addresses, 0x3100..=0x3100);
+        assert!(!watchpoint.read);
+        assert!(watchpoint.write);
+        assert_eq!(watchpoint.condition, Condition::Always);
+    }
+
+    #[test]
+    fn watch_parses_ranges_accesses_and_conditions() {
+        let reads = watch("w 0x3100 x310F read");
+        assert_eq!(reads.addresses, 0x3100..=0x310F);
+        assert!(reads.read && !reads.write);
+
+        let writes = watch("watch 12544 write changed");
+        assert_eq!(writes.addresses, 0x3100..=0x3100);
+        assert!(!writes.read && writes.write);
+        assert_eq!(writes.condition, Condition::Changed);
+
+        let accesses = watch("watch x3100 access equals x41");
+        assert!(accesses.read && accesses.write);
+        assert_eq!(accesses.condition, Condition::Equals(0x41));
+    }
+
+    #[test]
+    fn watch_rejects_bad_ranges_and_options() {
+        assert!(Command::parse("watch").is_err());
+        assert!(Command::parse("watch x3101 x3100").is_err());
+        assert!(Command::parse("watch x3100 sometimes").is_err());
+        assert!(Command::parse("watch x3100 equals").is_err());
+    }
+}
@@ -0,0 +1,278 @@
+//! Memory-mapped devices.
+//!
+//! Every [`Device`] on the [`DeviceBus`] claims a range of addresses in the
+//! device register page (0xFE00 to 0xFFFF). Reads and writes the program does
+//! in that range are handed to the device instead of going to memory, and
+//! before every instruction each device gets a chance to request an interrupt.
+
+use super::console::Console;
+use super::error::Fault;
+use super::state::{CLOCK_ENABLE, MACHINE_CONTROL_REGISTER};
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// Addresses devices may claim.
+pub const DEVICE_REGISTERS: RangeInclusive<u16> = 0xFE00..=0xFFFF;
+
+/// A peripheral whose registers are mapped into the device register page.
+pub trait Device {
+    /// The addresses of the device's registers. They must be in
+    /// [`DEVICE_REGISTERS`] and not overlap with other devices.
+    fn addresses(&self) -> RangeInclusive<u16>;
+    /// Called when the program reads one of the device's registers.
+    fn read(&mut self, address: u16, context: &mut DeviceContext) -> Result<u16, Fault>;
+    /// The value of one of the device's registers, without the side effects
+    /// a read by the program may have, e.g. for a debugger to show.
+    fn peek(&self, address: u16) -> u16;
+    /// Called when the program writes one of the device's registers.
+    fn write(&mut self, address: u16, value: u16, context: &mut DeviceContext)
+        -> Result<(), Fault>;
+    /// Called before every instruction, so the device can keep time, check
+    /// for input and request an interrupt. Whether the interrupt is taken
+    /// depends on the priority the processor is running at.
+    fn tick(&mut self, _context: &mut DeviceContext) -> Result<Option<Interrupt>, Fault> {
+        Ok(None)
+    }
+}
+
+impl fmt::Debug for dyn Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Device({:#06X}..={:#06X})",
+            self.addresses().start(),
+            self.addresses().end()
+        )
+    }
+}
+
+/// An interrupt request from a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupt {
+    /// Entry of the interrupt vector table holding the service routine
+    pub vector: u8,
+    /// Priority level, from 0 to 7. The interrupt is only taken when it is
+    /// higher than the one the processor is running at
+    pub priority: u16,
+}
+
+/// What a device gets to see of the rest of the machine.
+pub struct DeviceContext<'a> {
+    console: &'a mut dyn Console,
+    machine_control: &'a mut u16,
+    input_exhausted: &'a mut bool,
+}
+
+impl<'a> DeviceContext<'a> {
+    pub(crate) fn new(
+        console: &'a mut dyn Console,
+        machine_control: &'a mut u16,
+        input_exhausted: &'a mut bool,
+    ) -> DeviceContext<'a> {
+        DeviceContext {
+            console,
+            machine_control,
+            input_exhausted,
+        }
+    }
+
+    /// The console shared with the trap routines.
+    pub fn console(&mut self) -> &mut dyn Console {
+        self.console
+    }
+
+    /// Whether the console already ran out of input.
+    pub fn input_exhausted(&self) -> bool {
+        *self.input_exhausted
+    }
+
+    /// Records that the console ran out of input. A program waiting for a key
+    /// can never continue after that, so `stop` also stops the machine.
+    pub fn set_input_exhausted(&mut self, stop: bool) {
+        *self.input_exhausted = true;
+
+        if stop {
+            self.stop();
+        }
+    }
+
+    /// Stops the machine by clearing the clock enable bit of the machine
+    /// control register.
+    pub fn stop(&mut self) {
+        *self.machine_control &= !CLOCK_ENABLE;
+    }
+}
+
+/// The devices attached to the machine.
+#[derive(Debug, Default)]
+pub struct DeviceBus {
+    devices: Vec<Box<dyn Device>>,
+}
+
+impl DeviceBus {
+    pub fn new() -> DeviceBus {
+        DeviceBus::default()
+    }
+
+    /// Attaches a device to the bus.
+    ///
+    /// # Panics
+    ///
+    /// If the device claims addresses outside [`DEVICE_REGISTERS`], the
+    /// machine control register or addresses already claimed by another
+    /// device.
+    pub fn attach(&mut self, device: Box<dyn Device>) {
+        let addresses = device.addresses();
+        assert!(
+            DEVICE_REGISTERS.contains(addresses.start())
+                && DEVICE_REGISTERS.contains(addresses.end()),
+            "{:?} claims addresses outside the device register page",
+            device
+        );
+        assert!(
+            !addresses.contains(&MACHINE_CONTROL_REGISTER),
+            "{:?} claims the machine control register",
+            device
+        );
+        if let Some(other) = self.devices.iter().find(|other| {
+            let claimed = other.addresses();
+            claimed.start() <= addresses.end() && addresses.start() <= claimed.end()
+        }) {
+            panic!("{:?} overlaps with {:?}", device, other);
+        }
+
+        self.devices.push(device);
+    }
+
+    /// The device whose registers include `address`, if any.
+    pub fn find(&mut self, address: u16) -> Option<&mut Box<dyn Device>> {
+        self.devices
+            .iter_mut()
+            .find(|device| device.addresses().contains(&address))
+    }
+
+    /// The value of the device register at `address` without side effects,
+    /// see [`Device::peek`], or `None` if no device claims it.
+    pub fn peek(&self, address: u16) -> Option<u16> {
+        self.devices
+            .iter()
+            .find(|device| device.addresses().contains(&address))
+            .map(|device| device.peek(address))
+    }
+
+    /// Ticks every device, returning the highest priority interrupt any of
+    /// them requested.
+    pub fn tick(&mut self, context: &mut DeviceContext) -> Result<Option<Interrupt>, Fault> {
+        let mut pending: Option<Interrupt> = None;
+
+        for device in self.devices.iter_mut() {
+            if let Some(interrupt) = device.tick(context)? {
+                if pending.is_none_or(|pending| interrupt.priority > pending.priority) {
+                    pending = Some(interrupt);
+                }
+            }
+        }
+
+        Ok(pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::MemoryConsole;
+    use crate::state::State;
+
+    /// A device with one register per address, remembering what was written.
+    #[derive(Debug)]
+    struct Registers {
+        addresses: RangeInclusive<u16>,
+        values: Vec<u16>,
+    }
+
+    impl Registers {
+        fn new(addresses: RangeInclusive<u16>) -> Registers {
+            let count = addresses.len();
+            Registers {
+                addresses,
+                values: vec![0; count],
+            }
+        }
+    }
+
+    impl Device for Registers {
+        fn addresses(&self) -> RangeInclusive<u16> {
+            self.addresses.clone()
+        }
+
+        fn read(&mut self, address: u16, _context: &mut DeviceContext) -> Result<u16, Fault> {
+            Ok(self.values[(address - self.addresses.start()) as usize])
+        }
+
+        fn peek(&self, address: u16) -> u16 {
+            self.values[(address - self.addresses.start()) as usize]
+        }
+
+        fn write(
+            &mut self,
+            address: u16,
+            value: u16,
+            _context: &mut DeviceContext,
+        ) -> Result<(), Fault> {
+            self.values[(address - self.addresses.start()) as usize] = value;
+            Ok(())
+        }
+    }
+
+    fn state() -> State {
+        State::with_console(Box::new(MemoryConsole::new(&[])))
+    }
+
+    #[test]
+    fn find_routes_addresses_to_the_device_claiming_them() {
+        let mut bus = DeviceBus::new();
+        bus.attach(Box::new(Registers::new(0xFE10..=0xFE11)));
+        bus.attach(Box::new(Registers::new(0xFE12..=0xFE12)));
+
+        assert_eq!(bus.find(0xFE11).unwrap().addresses(), 0xFE10..=0xFE11);
+        assert_eq!(bus.find(0xFE12).unwrap().addresses(), 0xFE12..=0xFE12);
+        assert!(bus.find(0xFE13).is_none());
+        assert!(bus.find(0x3000).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn overlapping_devices_are_rejected() {
+        let mut bus = DeviceBus::new();
+        bus.attach(Box::new(Registers::new(0xFE10..=0xFE13)));
+        bus.attach(Box::new(Registers::new(0xFE13..=0xFE14)));
+    }
+
+    #[test]
+    #[should_panic(expected = "outside the device register page")]
+    fn devices_outside_the_device_register_page_are_rejected() {
+        DeviceBus::new().attach(Box::new(Registers::new(0xFDFF..=0xFE00)));
+    }
+
+    #[test]
+    #[should_panic(expected = "machine control register")]
+    fn the_machine_control_register_cannot_be_claimed() {
+        DeviceBus::new().attach(Box::new(Registers::new(0xFFF0..=0xFFFF)));
+    }
+
+    #[test]
+    fn accesses_go_to_the_device_or_fall_through_to_memory() {
+        let mut state = state();
+        state
+            .devices
+            .attach(Box::new(Registers::new(0xFE20..=0xFE21)));
+
+        state.mem_set(0xFE21, 0x1234).unwrap();
+        assert_eq!(state.mem_read(0xFE21).unwrap(), 0x1234);
+        assert_eq!(state.memory[0xFE21], 0);
+
+        state.mem_set(0xFE30, 0x5678).unwrap();
+        assert_eq!(state.memory[0xFE30], 0x5678);
+        assert_eq!(state.mem_read(0xFE30).unwrap(), 0x5678);
+    }
+}
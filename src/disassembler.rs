@@ -1,24 +1,38 @@
+//! Turns LC-3 machine code back into assembly.
+//!
+//! There are no agreed upon code and data sections in an LC-3 object file, so
+//! every word is decoded as an instruction.
+
 use super::opcode::{Opcode, Trapcode};
 
-pub fn disassemble(code: &Vec<u16>, pc_start: u16) {
-    let mut pc = pc_start;
-
-    for instruction in code {
-        print!(
-            "0x{:X} {:04b} {:04b} {:04b} {:04b} ",
-            pc,
-            instruction >> 12,
-            (instruction >> 8) & 0xF,
-            (instruction >> 4) & 0xF,
-            instruction & 0xF
-        );
-        pc += 1;
-
-        disassemble_op(*instruction, pc);
+/// Prints a listing of `code` to stdout, assuming its first word lives at
+/// `pc_start`. Every line holds the address, the instruction in binary and
+/// its disassembly.
+pub fn disassemble(code: &[u16], pc_start: u16) {
+    for (pc, instruction) in (pc_start..).zip(code) {
+        println!("{}", disassemble_line(*instruction, pc));
     }
 }
 
-fn disassemble_op(instruction: u16, pc: u16) {
+/// Formats a single listing line for `instruction` located at `address`, as
+/// printed by [`disassemble`].
+pub fn disassemble_line(instruction: u16, address: u16) -> String {
+    format!(
+        "0x{:X} {:04b} {:04b} {:04b} {:04b} {}",
+        address,
+        instruction >> 12,
+        (instruction >> 8) & 0xF,
+        (instruction >> 4) & 0xF,
+        instruction & 0xF,
+        disassemble_instruction(instruction, address)
+    )
+}
+
+/// Disassembles `instruction` located at `address`, e.g. `LEA R0 0x3003`.
+/// PC-relative operands are resolved to absolute addresses.
+pub fn disassemble_instruction(instruction: u16, address: u16) -> String {
+    // PC-relative offsets are computed from the incremented PC
+    let pc = address + 1;
     let opcode = instruction >> 12;
 
     match opcode.try_into() {
@@ -30,23 +44,19 @@ fn disassemble_op(instruction: u16, pc: u16) {
         Ok(Opcode::AND) => and(instruction),
         Ok(Opcode::LDR) => load_base_plus_offset(instruction),
         Ok(Opcode::STR) => store_base_plus_offset(instruction),
-        Ok(Opcode::RTI) => {
-            unimplemented!()
-        }
+        Ok(Opcode::RTI) => "RTI".to_string(),
         Ok(Opcode::NOT) => not(instruction),
         Ok(Opcode::LDI) => load_indirect(instruction, pc),
         Ok(Opcode::STI) => store_indirect(instruction, pc),
         Ok(Opcode::JMP) => jump(instruction),
-        Ok(Opcode::RES) => illegal_opcode(),
+        Ok(Opcode::RES) => "RES".to_string(),
         Ok(Opcode::LEA) => load_effective_address(instruction, pc),
         Ok(Opcode::TRAP) => trap(instruction),
-        Err(_) => {
-            panic!("Unknown Opcode: {}", opcode);
-        }
+        Err(_) => unreachable!("Opcodes are four bits wide"),
     }
 }
 
-fn conditional_branch(instruction: u16, pc: u16) {
+fn conditional_branch(instruction: u16, pc: u16) -> String {
     let n = (instruction >> 11) & 0x1;
     let z = (instruction >> 10) & 0x1;
     let p = (instruction >> 9) & 0x1;
@@ -57,124 +67,120 @@ fn conditional_branch(instruction: u16, pc: u16) {
     let z_character = if z == 1 { "z" } else { "" };
     let p_character = if p == 1 { "p" } else { "" };
 
-    println!(
+    format!(
         "BR{}{}{} 0x{:X}",
         n_character,
         z_character,
         p_character,
         pc + offset
-    );
+    )
 }
 
-fn add(instruction: u16) {
+fn add(instruction: u16) -> String {
     let dr = get_dr(instruction);
     let sr1 = (instruction & 0b0001_1100_0000) >> 6;
 
     if instruction & 0x10 == 0 {
         let sr2 = instruction & 0x0007;
-        println!("ADD R{} R{} R{}", dr, sr1, sr2);
+        format!("ADD R{} R{} R{}", dr, sr1, sr2)
     } else {
         let imm5 = instruction & 0x001F;
-        println!("ADD IMM R{} R{} 0x{:X}", dr, sr1, imm5);
+        format!("ADD IMM R{} R{} 0x{:X}", dr, sr1, imm5)
     }
 }
 
-fn load(instruction: u16, pc: u16) {
+fn load(instruction: u16, pc: u16) -> String {
     let dr = get_dr(instruction);
     let offset = sign_extend(instruction & 0x01FF, 9);
 
-    println!("LD R{} 0x{:X}", dr, pc + offset)
+    format!("LD R{} 0x{:X}", dr, pc + offset)
 }
 
-fn store(instruction: u16, pc: u16) {
+fn store(instruction: u16, pc: u16) -> String {
     let sr = (instruction & 0b1110_0000_0000) >> 9;
     let offset = sign_extend(instruction & 0x01FF, 9);
 
-    println!("ST R{} 0x{:X}", sr, pc + offset);
+    format!("ST R{} 0x{:X}", sr, pc + offset)
 }
 
-fn jump_to_subroutine(instruction: u16, pc: u16) {
+fn jump_to_subroutine(instruction: u16, pc: u16) -> String {
     if (instruction >> 11) & 0x1 == 0 {
         let base_register = (instruction >> 6) & 0x07;
-        println!("JSRR R{}", base_register);
+        format!("JSRR R{}", base_register)
     } else {
         let offset = sign_extend(instruction & 0x07FF, 11);
-        println!("JSR 0x{:X}", pc + offset);
+        format!("JSR 0x{:X}", pc + offset)
     }
 }
 
-fn and(instruction: u16) {
+fn and(instruction: u16) -> String {
     let dr = get_dr(instruction);
     let sr1 = (instruction & 0b0001_1100_0000) >> 6;
 
     if instruction & 0x10 == 0 {
         let sr2 = instruction & 0x0007;
-        println!("AND R{} R{} R{}", dr, sr1, sr2);
+        format!("AND R{} R{} R{}", dr, sr1, sr2)
     } else {
         let imm5 = instruction & 0x001F;
-        println!("AND IMM R{} R{} 0x{:X}", dr, sr1, imm5);
+        format!("AND IMM R{} R{} 0x{:X}", dr, sr1, imm5)
     }
 }
 
-fn load_base_plus_offset(instruction: u16) {
+fn load_base_plus_offset(instruction: u16) -> String {
     let dr = get_dr(instruction);
     let base_register = (instruction >> 6) & 0x07;
     let offset = sign_extend(instruction & 0x003F, 6);
 
-    println!("LDR R{} R{} 0x{:X}", dr, base_register, offset);
+    format!("LDR R{} R{} 0x{:X}", dr, base_register, offset)
 }
 
-fn store_base_plus_offset(instruction: u16) {
+fn store_base_plus_offset(instruction: u16) -> String {
     let sr = (instruction & 0b1110_0000_0000) >> 9;
     let base_register = (instruction >> 6) & 0x07;
     let offset = sign_extend(instruction & 0x003F, 6);
 
-    println!("STR R{} R{} 0x{:X}", sr, base_register, offset);
+    format!("STR R{} R{} 0x{:X}", sr, base_register, offset)
 }
 
-fn not(instruction: u16) {
+fn not(instruction: u16) -> String {
     let dr = get_dr(instruction);
     let sr = (instruction & 0b0001_1100_0000) >> 6;
 
-    println!("NOT R{} R{}", dr, sr);
+    format!("NOT R{} R{}", dr, sr)
 }
 
-fn load_indirect(instruction: u16, pc: u16) {
+fn load_indirect(instruction: u16, pc: u16) -> String {
     let dr = get_dr(instruction);
     let offset = sign_extend(instruction & 0x01FF, 9);
 
-    println!("LDI R{} 0x{:x}", dr, pc + offset);
+    format!("LDI R{} 0x{:x}", dr, pc + offset)
 }
 
-fn store_indirect(instruction: u16, pc: u16) {
+fn store_indirect(instruction: u16, pc: u16) -> String {
     let sr = (instruction & 0b1110_0000_0000) >> 9;
     let offset = sign_extend(instruction & 0x01FF, 9);
 
-    println!("STI R{} 0x{:X}", sr, pc + offset);
+    format!("STI R{} 0x{:X}", sr, pc + offset)
 }
 
-fn jump(instruction: u16) {
+fn jump(instruction: u16) -> String {
     let base_register = (instruction >> 6) & 0x07;
 
     if base_register != 0b111 {
-        println!("JMP R{}", base_register);
+        format!("JMP R{}", base_register)
     } else {
-        println!("RET");
+        "RET".to_string()
     }
 }
 
-fn illegal_opcode() {
-    panic!("Illegal opcode encountered");
-}
-
-fn load_effective_address(instruction: u16, pc: u16) {
+fn load_effective_address(instruction: u16, pc: u16) -> String {
     let dr = get_dr(instruction);
     let offset = sign_extend(instruction & 0x01FF, 9);
 
-    println!("LEA R{} 0x{:X}", dr, pc + offset);
+    format!("LEA R{} 0x{:X}", dr, pc + offset)
 }
 
-fn trap(instruction: u16) {
+fn trap(instruction: u16) -> String {
     let trap_code = instruction & 0xFF;
 
     let trap_vector = match trap_code.try_into() {
@@ -187,10 +193,10 @@ fn trap(instruction: u16) {
         _ => "",
     };
 
-    if trap_vector != "" {
-        println!("TRAP {}", trap_vector);
+    if !trap_vector.is_empty() {
+        format!("TRAP {}", trap_vector)
     } else {
-        println!("TRAP {}", trap_code);
+        format!("TRAP {}", trap_code)
     }
 }
 
@@ -203,5 +209,5 @@ fn sign_extend(value: u16, bit_count: u16) -> u16 {
 }
 
 fn get_dr(instruction: u16) -> u16 {
-    return (instruction & 0b1110_0000_0000) >> 9;
+    (instruction & 0b1110_0000_0000) >> 9
 }
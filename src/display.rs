@@ -0,0 +1,57 @@
+use super::device::{Device, DeviceContext};
+use super::error::Fault;
+use std::ops::RangeInclusive;
+
+pub const DISPLAY_STATUS_REGISTER: u16 = 0xFE04;
+pub const DISPLAY_DATA_REGISTER: u16 = 0xFE06;
+
+/// Bit of the display status register set when the display can take a
+/// character
+const DISPLAY_READY: u16 = 1 << 15;
+
+/// The display, writing characters stored in its data register (DDR) to the
+/// console once its status register (DSR) reports it is ready.
+#[derive(Debug, Default)]
+pub struct Display;
+
+impl Display {
+    pub fn new() -> Display {
+        Display
+    }
+}
+
+impl Device for Display {
+    fn addresses(&self) -> RangeInclusive<u16> {
+        DISPLAY_STATUS_REGISTER..=DISPLAY_DATA_REGISTER + 1
+    }
+
+    fn read(&mut self, address: u16, _context: &mut DeviceContext) -> Result<u16, Fault> {
+        // Our display takes characters as fast as we can write them, so it is
+        // always ready for the next one.
+        match address {
+            DISPLAY_STATUS_REGISTER => Ok(DISPLAY_READY),
+            _ => Ok(0),
+        }
+    }
+
+    fn peek(&self, address: u16) -> u16 {
+        match address {
+            DISPLAY_STATUS_REGISTER => DISPLAY_READY,
+            _ => 0,
+        }
+    }
+
+    fn write(
+        &mut self,
+        address: u16,
+        value: u16,
+        context: &mut DeviceContext,
+    ) -> Result<(), Fault> {
+        if address == DISPLAY_DATA_REGISTER {
+            context.console().write_byte(value as u8)?;
+            context.console().flush()?;
+        }
+
+        Ok(())
+    }
+}
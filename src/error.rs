@@ -0,0 +1,149 @@
+use std::fmt;
+
+/// Everything that can go wrong while loading or running a program.
+#[derive(Debug)]
+pub enum VmError {
+    /// Execution stopped at an instruction that could not be completed
+    Fault {
+        fault: Fault,
+        /// The machine state right after the faulting instruction was fetched
+        snapshot: Snapshot,
+    },
+    /// An image could not be loaded into memory
+    Image(ImageError),
+}
+
+/// Why an instruction could not be executed.
+#[derive(Debug)]
+pub enum Fault {
+    /// The reserved opcode was encountered, carries the instruction
+    IllegalOpcode(u16),
+    /// A TRAP with a vector that has no service routine, carries the vector
+    UnknownTrap(u8),
+    /// An instruction that needs supervisor mode ran in user mode, carries
+    /// the instruction
+    PrivilegeViolation(u16),
+    /// A user mode program touched system space or the device registers,
+    /// carries the address
+    AccessViolation(u16),
+    /// Reading from or writing to the console failed
+    Io(std::io::Error),
+}
+
+/// Why an image could not be loaded.
+#[derive(Debug)]
+pub enum ImageError {
+    /// The object file could not be read
+    Read(std::io::Error),
+    /// The image does not even hold an origin
+    Empty,
+    /// The image runs past the end of memory
+    DoesNotFit { origin: u16, len: usize },
+}
+
+/// The registers, program counter and processor status register at a given
+/// point of the execution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    pub registers: [u16; 8],
+    pub pc: u16,
+    pub psr: u16,
+}
+
+impl Fault {
+    /// The entry of the interrupt vector table for the exception this fault
+    /// corresponds to, if any.
+    pub fn exception_vector(&self) -> Option<u8> {
+        match self {
+            Fault::PrivilegeViolation(_) => Some(0x00),
+            Fault::IllegalOpcode(_) => Some(0x01),
+            Fault::AccessViolation(_) => Some(0x02),
+            Fault::UnknownTrap(_) | Fault::Io(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Fault {
+    fn from(error: std::io::Error) -> Self {
+        Fault::Io(error)
+    }
+}
+
+impl From<ImageError> for VmError {
+    fn from(error: ImageError) -> Self {
+        VmError::Image(error)
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::Fault { fault, snapshot } => write!(f, "{}\n{}", fault, snapshot),
+            VmError::Image(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::IllegalOpcode(instruction) => {
+                write!(f, "Illegal opcode encountered: 0x{:04X}", instruction)
+            }
+            Fault::UnknownTrap(trap_code) => write!(f, "Unexpected trap code: 0x{:X}", trap_code),
+            Fault::PrivilegeViolation(instruction) => write!(
+                f,
+                "Privilege mode violation: 0x{:04X} needs supervisor mode",
+                instruction
+            ),
+            Fault::AccessViolation(address) => write!(
+                f,
+                "Access control violation: user mode access to 0x{:04X}",
+                address
+            ),
+            Fault::Io(error) => write!(f, "Console I/O error: {}", error),
+        }
+    }
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Read(error) => write!(f, "Could not read image: {}", error),
+            ImageError::Empty => write!(f, "Image is empty"),
+            ImageError::DoesNotFit { origin, len } => write!(
+                f,
+                "Image of {} words at origin 0x{:X} does not fit in memory",
+                len, origin
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Registers: {:?}\nPc: 0x{:x}\nPsr: 0x{:04x}\nZF: {}\nNF: {}\nPF: {}",
+            self.registers,
+            self.pc,
+            self.psr,
+            (self.psr >> 1) & 0x1,
+            (self.psr >> 2) & 0x1,
+            self.psr & 0x1
+        )
+    }
+}
+
+impl std::error::Error for VmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VmError::Fault {
+                fault: Fault::Io(error),
+                ..
+            } => Some(error),
+            VmError::Image(ImageError::Read(error)) => Some(error),
+            _ => None,
+        }
+    }
+}
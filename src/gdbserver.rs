@@ -0,0 +1,581 @@
+//! A [GDB Remote Serial Protocol](https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html)
+//! server, so GDB front-ends and scripts can drive the VM.
+//!
+//! Registers 0 to 7 are R0 to R7, 8 is the PC and 9 the PSR, all 16 bits
+//! wide. Memory is addressed by word like the LC-3 does, so addresses match
+//! the PC and breakpoints, and every word is sent as two bytes, most
+//! significant first. Lengths are in bytes.
+
+use super::error::{Fault, VmError};
+use super::vm::{StepOutcome, Vm, INPUT_POLL_INTERVAL};
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// Register numbers of the PC and the PSR, right after R0 through R7.
+const PC_REGISTER: usize = 8;
+const PSR_REGISTER: usize = 9;
+const REGISTER_COUNT: usize = 10;
+
+/// Largest packet the server accepts, and the size its replies to memory
+/// reads are kept to.
+const PACKET_SIZE: usize = 0x1000;
+
+/// How many instructions run between checks for a break from the debugger
+/// while continuing.
+const INTERRUPT_CHECK_INTERVAL: u32 = 1024;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+const TARGET_DESCRIPTION: &str = concat!(
+    r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd">"#,
+    r#"<target><feature name="org.lc3.core">"#,
+    r#"<reg name="r0" bitsize="16" type="uint16"/>"#,
+    r#"<reg name="r1" bitsize="16" type="uint16"/>"#,
+    r#"<reg name="r2" bitsize="16" type="uint16"/>"#,
+    r#"<reg name="r3" bitsize="16" type="uint16"/>"#,
+    r#"<reg name="r4" bitsize="16" type="uint16"/>"#,
+    r#"<reg name="r5" bitsize="16" type="uint16"/>"#,
+    r#"<reg name="r6" bitsize="16" type="data_ptr"/>"#,
+    r#"<reg name="r7" bitsize="16" type="code_ptr"/>"#,
+    r#"<reg name="pc" bitsize="16" type="code_ptr"/>"#,
+    r#"<reg name="psr" bitsize="16" type="uint16"/>"#,
+    r#"</feature></target>"#
+);
+
+/// Why the program stopped, as reported to the debugger.
+enum Stop {
+    Signal(u8),
+    Exited,
+}
+
+/// What a single step of the program did.
+enum Progress {
+    /// An instruction ran and the program goes on
+    Executed,
+    /// The program needs a key, the PC still points at the TRAP reading it
+    WaitingForInput,
+    Stopped(Stop),
+}
+
+/// Serves a [`Vm`] to a GDB client.
+#[derive(Debug)]
+pub struct GdbServer {
+    vm: Vm,
+    breakpoints: BTreeSet<u16>,
+}
+
+impl GdbServer {
+    pub fn new(vm: Vm) -> GdbServer {
+        GdbServer {
+            vm,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    /// Waits for a debugger to connect to `port` on localhost and serves it
+    /// until it detaches or kills the program.
+    pub fn listen(&mut self, port: u16) -> std::io::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        let (stream, _) = listener.accept()?;
+
+        self.serve(stream)
+    }
+
+    /// Serves a debugger already connected through `stream`.
+    pub fn serve(&mut self, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_nodelay(true)?;
+
+        while let Some(packet) = read_packet(&mut stream)? {
+            match packet.as_str() {
+                "k" => return Ok(()),
+                "D" | "D;1" => {
+                    send_packet(&mut stream, "OK")?;
+                    return Ok(());
+                }
+                _ => {
+                    let reply = self.handle(&packet, &mut stream)?;
+                    send_packet(&mut stream, &reply)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Executes a packet and returns the reply. Unsupported packets get an
+    /// empty reply, as the protocol asks.
+    fn handle(&mut self, packet: &str, stream: &mut TcpStream) -> std::io::Result<String> {
+        let mut characters = packet.chars();
+        let command = characters.next();
+        let arguments = characters.as_str();
+
+        let reply = match command {
+            Some('?') => stop_reply(&Stop::Signal(SIGTRAP)),
+            Some('q') => self.query(arguments),
+            Some('H') => "OK".to_string(),
+            Some('g') => (0..REGISTER_COUNT)
+                .map(|register| format!("{:04x}", self.register(register)))
+                .collect(),
+            Some('G') => self.write_registers(arguments),
+            Some('p') => match parse_hex(arguments) {
+                Some(register) if register < REGISTER_COUNT => {
+                    format!("{:04x}", self.register(register))
+                }
+                _ => "E01".to_string(),
+            },
+            Some('P') => match arguments.split_once('=') {
+                Some((register, value)) => match (parse_hex(register), parse_hex(value)) {
+                    (Some(register), Some(value)) if register < REGISTER_COUNT => {
+                        self.set_register(register, value as u16);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                },
+                None => "E01".to_string(),
+            },
+            Some('m') => self.read_memory(arguments),
+            Some('M') => self.write_memory(arguments),
+            Some('s') | Some('c') => {
+                if let Some(address) = parse_hex(arguments) {
+                    self.vm.state_mut().pc = address as u16;
+                }
+                let stop = if command == Some('s') {
+                    self.single_step(stream)?
+                } else {
+                    self.resume(stream)?
+                };
+                stop_reply(&stop)
+            }
+            Some('Z') | Some('z') => self.breakpoint(command == Some('Z'), arguments),
+            _ => String::new(),
+        };
+
+        Ok(reply)
+    }
+
+    fn query(&self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE)
+        } else if query == "Attached" {
+            "1".to_string()
+        } else if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            let range = range
+                .split_once(',')
+                .and_then(|(offset, length)| Some((parse_hex(offset)?, parse_hex(length)?)));
+
+            match range {
+                Some((offset, length)) => {
+                    let description = TARGET_DESCRIPTION.as_bytes();
+                    let start = offset.min(description.len());
+                    let end = offset.saturating_add(length).min(description.len());
+                    let more = if end < description.len() { "m" } else { "l" };
+
+                    format!("{}{}", more, &TARGET_DESCRIPTION[start..end])
+                }
+                None => "E01".to_string(),
+            }
+        } else {
+            String::new()
+        }
+    }
+
+    fn register(&self, register: usize) -> u16 {
+        let state = self.vm.state();
+
+        match register {
+            PC_REGISTER => state.pc,
+            PSR_REGISTER => state.psr,
+            _ => state.registers[register],
+        }
+    }
+
+    fn set_register(&mut self, register: usize, value: u16) {
+        let state = self.vm.state_mut();
+
+        match register {
+            PC_REGISTER => state.pc = value,
+            PSR_REGISTER => state.psr = value,
+            _ => state.registers[register] = value,
+        }
+    }
+
+    fn write_registers(&mut self, values: &str) -> String {
+        let values: Option<Vec<usize>> = (0..REGISTER_COUNT)
+            .map(|register| parse_hex(values.get(register * 4..register * 4 + 4)?))
+            .collect();
+
+        match values {
+            Some(values) => {
+                for (register, value) in values.into_iter().enumerate() {
+                    self.set_register(register, value as u16);
+                }
+                "OK".to_string()
+            }
+            None => "E01".to_string(),
+        }
+    }
+
+    /// Reads memory as stored, without the side effects reading the device
+    /// registers would have. Reads that don't fit in a packet are cut short,
+    /// and the debugger asks for the rest.
+    fn read_memory(&self, arguments: &str) -> String {
+        let Some((address, length)) = parse_range(arguments) else {
+            return "E01".to_string();
+        };
+        let length = length.min(PACKET_SIZE / 2);
+        let memory = &self.vm.state().memory;
+
+        let mut words: String = (0..length.div_ceil(2))
+            .map(|index| {
+                let address = (address as u16).wrapping_add(index as u16);
+                format!("{:04x}", memory[address as usize])
+            })
+            .collect();
+        words.truncate(length * 2);
+
+        words
+    }
+
+    fn write_memory(&mut self, arguments: &str) -> String {
+        let Some((range, data)) = arguments.split_once(':') else {
+            return "E01".to_string();
+        };
+        let Some((address, length)) = parse_range(range) else {
+            return "E01".to_string();
+        };
+        let bytes: Option<Vec<u8>> = (0..length)
+            .map(|index| {
+                let byte = data.get(index * 2..index * 2 + 2)?;
+                u8::from_str_radix(byte, 16).ok()
+            })
+            .collect();
+        let Some(bytes) = bytes else {
+            return "E01".to_string();
+        };
+
+        let memory = &mut self.vm.state_mut().memory;
+        for (index, pair) in bytes.chunks(2).enumerate() {
+            let address = (address as u16).wrapping_add(index as u16) as usize;
+            memory[address] = match pair {
+                [high, low] => u16::from_be_bytes([*high, *low]),
+                // A lone byte only replaces the most significant half
+                _ => (memory[address] & 0x00FF) | ((pair[0] as u16) << 8),
+            };
+        }
+
+        "OK".to_string()
+    }
+
+    /// Sets (`insert`) or removes a software breakpoint. Other kinds of
+    /// breakpoints and watchpoints are not supported.
+    fn breakpoint(&mut self, insert: bool, arguments: &str) -> String {
+        let mut fields = arguments.split(',');
+        let kind = fields.next();
+        let address = fields.next().and_then(parse_hex);
+
+        match (kind, address) {
+            (Some("0"), Some(address)) => {
+                if insert {
+                    self.breakpoints.insert(address as u16);
+                } else {
+                    self.breakpoints.remove(&(address as u16));
+                }
+                "OK".to_string()
+            }
+            (Some("0"), None) => "E01".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// Runs until a breakpoint, the program stops or the debugger sends a
+    /// break. The instruction at the PC always runs, even if it has a
+    /// breakpoint.
+    fn resume(&mut self, stream: &mut TcpStream) -> std::io::Result<Stop> {
+        let mut executed: u32 = 0;
+
+        loop {
+            match self.step() {
+                Progress::Executed => {}
+                Progress::WaitingForInput => {
+                    if wait_for_input(stream)? {
+                        return Ok(Stop::Signal(SIGINT));
+                    }
+                    continue;
+                }
+                Progress::Stopped(stop) => return Ok(stop),
+            }
+            if self.breakpoints.contains(&self.vm.state().pc) {
+                return Ok(Stop::Signal(SIGTRAP));
+            }
+
+            executed = executed.wrapping_add(1);
+            if executed.is_multiple_of(INTERRUPT_CHECK_INTERVAL) && break_requested(stream)? {
+                return Ok(Stop::Signal(SIGINT));
+            }
+        }
+    }
+
+    /// Executes one instruction, waiting for input when the program needs a
+    /// key unless the debugger sends a break.
+    fn single_step(&mut self, stream: &mut TcpStream) -> std::io::Result<Stop> {
+        loop {
+            match self.step() {
+                Progress::Executed => return Ok(Stop::Signal(SIGTRAP)),
+                Progress::WaitingForInput => {
+                    if wait_for_input(stream)? {
+                        return Ok(Stop::Signal(SIGINT));
+                    }
+                }
+                Progress::Stopped(stop) => return Ok(stop),
+            }
+        }
+    }
+
+    /// Steps the VM once.
+    fn step(&mut self) -> Progress {
+        match self.vm.step() {
+            StepOutcome::Executed
+            | StepOutcome::Trapped(_)
+            | StepOutcome::Exception(_)
+            | StepOutcome::Interrupted(_) => Progress::Executed,
+            StepOutcome::WaitingForInput => Progress::WaitingForInput,
+            StepOutcome::Halted | StepOutcome::EndOfInput => Progress::Stopped(Stop::Exited),
+            StepOutcome::Faulted(VmError::Fault { fault, .. }) => {
+                let signal = match fault {
+                    Fault::IllegalOpcode(_) | Fault::PrivilegeViolation(_) => SIGILL,
+                    Fault::AccessViolation(_) => SIGSEGV,
+                    Fault::UnknownTrap(_) | Fault::Io(_) => SIGTRAP,
+                };
+                Progress::Stopped(Stop::Signal(signal))
+            }
+            StepOutcome::Faulted(VmError::Image(_)) => Progress::Stopped(Stop::Signal(SIGTRAP)),
+        }
+    }
+}
+
+fn stop_reply(stop: &Stop) -> String {
+    match stop {
+        Stop::Signal(signal) => format!("S{:02x}", signal),
+        Stop::Exited => "W00".to_string(),
+    }
+}
+
+/// Parses `address,length`.
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    let (address, length) = range.split_once(',')?;
+
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+fn parse_hex(number: &str) -> Option<usize> {
+    usize::from_str_radix(number, 16).ok()
+}
+
+/// Waits a little for the program's input to arrive. Returns whether the
+/// debugger sent a break meanwhile.
+fn wait_for_input(stream: &mut TcpStream) -> std::io::Result<bool> {
+    if break_requested(stream)? {
+        return Ok(true);
+    }
+    std::thread::sleep(INPUT_POLL_INTERVAL);
+
+    Ok(false)
+}
+
+/// Whether the debugger sent a break (Ctrl-C) while the program runs.
+fn break_requested(stream: &mut TcpStream) -> std::io::Result<bool> {
+    let mut byte = [0; 1];
+
+    stream.set_nonblocking(true)?;
+    let read = stream.read(&mut byte);
+    stream.set_nonblocking(false)?;
+
+    match read {
+        Ok(1) => Ok(byte[0] == 0x03),
+        Ok(_) => Ok(false),
+        Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+/// Reads the next packet, acknowledging it. Returns `None` once the debugger
+/// disconnects.
+fn read_packet(stream: &mut TcpStream) -> std::io::Result<Option<String>> {
+    loop {
+        // Skip acknowledgements and breaks sent while the program was stopped
+        match read_byte(stream)? {
+            Some(b'$') => {}
+            Some(_) => continue,
+            None => return Ok(None),
+        }
+
+        let mut data = Vec::new();
+        loop {
+            match read_byte(stream)? {
+                Some(b'#') => break,
+                Some(byte) => data.push(byte),
+                None => return Ok(None),
+            }
+        }
+
+        let mut checksum = [0; 2];
+        match stream.read_exact(&mut checksum) {
+            Ok(()) => {}
+            Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) => return Err(error),
+        }
+        let expected = std::str::from_utf8(&checksum)
+            .ok()
+            .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+
+        if expected == Some(checksum_of(&data)) {
+            stream.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+        stream.write_all(b"-")?;
+    }
+}
+
+fn read_byte(stream: &mut TcpStream) -> std::io::Result<Option<u8>> {
+    let mut byte = [0; 1];
+
+    match stream.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+fn send_packet(stream: &mut TcpStream, data: &str) -> std::io::Result<()> {
+    let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+
+    stream.write_all(packet.as_bytes())?;
+    stream.flush()
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::MemoryConsole;
+    use std::thread::JoinHandle;
+
+    /// Serves `program`, loaded at 0x3000, to a debugger connecting through
+    /// the returned stream.
+    fn connect(program: &'static [u16]) -> (TcpStream, JoinHandle<()>) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            let mut vm = Vm::with_console(Box::new(MemoryConsole::new(&[])));
+            let mut image = vec![0x3000];
+            image.extend(program);
+            vm.load_image(&image).unwrap();
+
+            let (stream, _) = listener.accept().unwrap();
+            GdbServer::new(vm).serve(stream).unwrap();
+        });
+
+        (TcpStream::connect(address).unwrap(), server)
+    }
+
+    fn send(stream: &mut TcpStream, data: &str) {
+        send_packet(stream, data).unwrap();
+    }
+
+    /// Reads the acknowledgement of the last packet sent and the reply to it.
+    fn reply(stream: &mut TcpStream) -> String {
+        assert_eq!(read_byte(stream).unwrap(), Some(b'+'));
+        read_packet(stream).unwrap().unwrap()
+    }
+
+    fn request(stream: &mut TcpStream, data: &str) -> String {
+        send(stream, data);
+        reply(stream)
+    }
+
+    fn kill(mut stream: TcpStream, server: JoinHandle<()>) {
+        send(&mut stream, "k");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn packets_with_a_bad_checksum_are_rejected() {
+        let (mut stream, server) = connect(&[0xF025]);
+
+        stream.write_all(b"$?#00").unwrap();
+        assert_eq!(read_byte(&mut stream).unwrap(), Some(b'-'));
+        // The debugger sends it again, this time intact.
+        assert_eq!(request(&mut stream, "?"), "S05");
+
+        kill(stream, server);
+    }
+
+    #[test]
+    fn memory_addresses_are_words_and_lengths_bytes() {
+        let (mut stream, server) = connect(&[0x1234, 0xABCD]);
+
+        assert_eq!(request(&mut stream, "m3000,4"), "1234abcd");
+        assert_eq!(request(&mut stream, "m3001,1"), "ab");
+        assert_eq!(request(&mut stream, "M3001,2:beef"), "OK");
+        assert_eq!(request(&mut stream, "M3000,1:56"), "OK");
+        assert_eq!(request(&mut stream, "m3000,4"), "5634beef");
+
+        kill(stream, server);
+    }
+
+    #[test]
+    fn memory_reads_are_kept_to_the_packet_size() {
+        let (mut stream, server) = connect(&[0xF025]);
+
+        let words = request(&mut stream, "m0,ffff");
+        assert_eq!(words.len(), PACKET_SIZE);
+
+        kill(stream, server);
+    }
+
+    #[test]
+    fn breakpoints_are_inserted_and_removed() {
+        // ADD R0, R0, #1 twice, then HALT
+        let (mut stream, server) = connect(&[0x1021, 0x1021, 0xF025]);
+
+        assert_eq!(request(&mut stream, "Z0,3002,2"), "OK");
+        assert_eq!(request(&mut stream, "c"), "S05");
+        assert_eq!(request(&mut stream, "p8"), "3002");
+        assert_eq!(request(&mut stream, "p0"), "0002");
+
+        assert_eq!(request(&mut stream, "z0,3002,2"), "OK");
+        assert_eq!(request(&mut stream, "c"), "W00");
+
+        kill(stream, server);
+    }
+
+    #[test]
+    fn a_break_stops_a_running_program() {
+        // ADD R0, R0, #0 to set the condition codes, then BRnzp #-1
+        let (mut stream, server) = connect(&[0x1020, 0x0FFF]);
+
+        send(&mut stream, "c");
+        stream.write_all(&[0x03]).unwrap();
+        assert_eq!(reply(&mut stream), "S02");
+        assert_eq!(request(&mut stream, "p8"), "3001");
+
+        kill(stream, server);
+    }
+
+    #[test]
+    fn a_debugger_disconnecting_mid_packet_ends_the_session() {
+        let (mut stream, server) = connect(&[0xF025]);
+
+        stream.write_all(b"$?#0").unwrap();
+        drop(stream);
+
+        server.join().unwrap();
+    }
+}
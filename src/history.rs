@@ -0,0 +1,265 @@
+//! Undo log for reverse execution.
+//!
+//! While recording, every [`Vm::step`](crate::Vm::step) that executes an
+//! instruction or takes an interrupt adds a [`Delta`] holding the registers
+//! from before the step and the memory it wrote. Undoing the deltas in reverse
+//! order takes the machine back in time. Input already consumed and output
+//! already printed can't be taken back, and neither can the internal state of
+//! the devices.
+
+use super::callstack::CallEvent;
+use super::state::{State, MACHINE_CONTROL_REGISTER};
+use std::collections::VecDeque;
+
+/// A word of memory written by the machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub address: u16,
+    /// Value in memory before the write
+    pub old: u16,
+    pub value: u16,
+}
+
+/// Everything a single step changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delta {
+    /// Registers R0 through R7 before the step
+    pub registers: [u16; 8],
+    /// Program counter before the step, i.e. the address of the instruction
+    /// unless an interrupt was taken
+    pub pc: u16,
+    /// Processor status register before the step
+    pub psr: u16,
+    /// Saved user stack pointer before the step
+    pub saved_usp: u16,
+    /// Saved supervisor stack pointer before the step
+    pub saved_ssp: u16,
+    /// Machine control register before the step, which halting changes
+    /// without writing memory
+    pub machine_control: u16,
+    /// Memory written during the step, in order
+    pub writes: Vec<MemoryWrite>,
+    /// Whether the step took an interrupt rather than executing an
+    /// instruction
+    pub interrupted: bool,
+    /// How the step changed the [`Vm::call_stack`](crate::Vm::call_stack)
+    pub call: Option<CallEvent>,
+}
+
+impl Delta {
+    /// Records the registers of `state` before a step. The writes and what
+    /// the step did are added once it is done.
+    pub(crate) fn capture(state: &State) -> Delta {
+        Delta {
+            registers: state.registers,
+            pc: state.pc,
+            psr: state.psr,
+            saved_usp: state.saved_usp,
+            saved_ssp: state.saved_ssp,
+            machine_control: state.memory[MACHINE_CONTROL_REGISTER as usize],
+            writes: Vec::new(),
+            interrupted: false,
+            call: None,
+        }
+    }
+
+    /// Puts `state` back the way it was before the step.
+    pub(crate) fn undo(&self, state: &mut State) {
+        for write in self.writes.iter().rev() {
+            state.memory[write.address as usize] = write.old;
+        }
+
+        state.memory[MACHINE_CONTROL_REGISTER as usize] = self.machine_control;
+        state.registers = self.registers;
+        state.pc = self.pc;
+        state.psr = self.psr;
+        state.saved_usp = self.saved_usp;
+        state.saved_ssp = self.saved_ssp;
+    }
+}
+
+/// The most recent steps, oldest first, up to a maximum number of them.
+#[derive(Debug)]
+pub struct History {
+    deltas: VecDeque<Delta>,
+    capacity: usize,
+}
+
+impl History {
+    /// Creates a history keeping the last `capacity` steps.
+    pub fn new(capacity: usize) -> History {
+        History {
+            deltas: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /// Records a step, forgetting the oldest one if the history is full.
+    pub fn push(&mut self, delta: Delta) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.deltas.len() == self.capacity {
+            self.deltas.pop_front();
+        }
+
+        self.deltas.push_back(delta);
+    }
+
+    /// Forgets every recorded step.
+    pub fn clear(&mut self) {
+        self.deltas.clear();
+    }
+
+    /// Removes the most recent step.
+    pub fn pop(&mut self) -> Option<Delta> {
+        self.deltas.pop_back()
+    }
+
+    /// The recorded steps, oldest first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Delta> {
+        self.deltas.iter()
+    }
+
+    /// The most recent step that wrote `address`, along with how many steps
+    /// ago it was, counting the most recent step as 1.
+    pub fn last_writer(&self, address: u16) -> Option<(usize, &Delta, &MemoryWrite)> {
+        self.deltas
+            .iter()
+            .rev()
+            .enumerate()
+            .find_map(|(index, delta)| {
+                delta
+                    .writes
+                    .iter()
+                    .rev()
+                    .find(|write| write.address == address)
+                    .map(|write| (index + 1, delta, write))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::console::MemoryConsole;
+    use crate::Vm;
+
+    /// Stores R0 + 5 and then R0 + 6 to 0x3005, and halts.
+    fn vm() -> Vm {
+        let mut vm = Vm::with_console(Box::new(MemoryConsole::new(&[])));
+        vm.load_image(&[
+            0x3000, // origin
+            0x1025, // ADD R0, R0, #5
+            0x3003, // ST R0, RESULT
+            0x1021, // ADD R0, R0, #1
+            0x3001, // ST R0, RESULT
+            0xF025, // HALT
+            0x0000, // RESULT: .FILL 0
+        ])
+        .unwrap();
+        vm.record_history(16);
+        vm
+    }
+
+    #[test]
+    fn steps_are_undone_and_redone() {
+        let mut vm = vm();
+        vm.step();
+        vm.step();
+        let (registers, psr) = (vm.state().registers, vm.state().psr);
+
+        vm.step();
+        vm.step();
+        assert_eq!(vm.state().memory[0x3005], 6);
+
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert_eq!(vm.state().pc, 0x3002);
+        assert_eq!(vm.state().registers, registers);
+        assert_eq!(vm.state().psr, psr);
+        assert_eq!(vm.state().memory[0x3005], 5);
+        assert_eq!(vm.history().unwrap().len(), 2);
+
+        vm.step();
+        vm.step();
+        assert_eq!(vm.state().pc, 0x3004);
+        assert_eq!(vm.state().registers[0], 6);
+        assert_eq!(vm.state().memory[0x3005], 6);
+    }
+
+    #[test]
+    fn halting_is_undone() {
+        let mut vm = vm();
+        while vm.state().running() {
+            vm.step();
+        }
+
+        assert!(vm.step_back());
+        assert!(vm.state().running());
+        assert_eq!(vm.state().pc, 0x3004);
+    }
+
+    #[test]
+    fn stepping_back_stops_at_the_start_of_the_history() {
+        let mut vm = vm();
+        vm.step();
+
+        assert!(vm.step_back());
+        assert!(!vm.step_back());
+        assert_eq!(vm.state().pc, 0x3000);
+        assert_eq!(vm.state().registers[0], 0);
+    }
+
+    #[test]
+    fn only_the_last_steps_are_kept() {
+        let mut vm = vm();
+        vm.record_history(2);
+        for _ in 0..4 {
+            vm.step();
+        }
+
+        let history = vm.history().unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.iter().next().unwrap().pc, 0x3002);
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert!(!vm.step_back());
+        assert_eq!(vm.state().pc, 0x3002);
+    }
+
+    #[test]
+    fn writing_memory_forgets_the_history() {
+        let mut vm = vm();
+        vm.step();
+        vm.step();
+
+        vm.write_memory(0x3005, &[42]);
+
+        assert!(vm.history().unwrap().is_empty());
+        assert!(!vm.step_back());
+        assert_eq!(vm.state().memory[0x3005], 42);
+    }
+
+    #[test]
+    fn last_writer_finds_the_most_recent_store() {
+        let mut vm = vm();
+        for _ in 0..5 {
+            vm.step();
+        }
+
+        let history = vm.history().unwrap();
+        let (ago, delta, write) = history.last_writer(0x3005).unwrap();
+        assert_eq!(ago, 2);
+        assert_eq!(delta.pc, 0x3003);
+        assert_eq!((write.old, write.value), (5, 6));
+        assert!(history.last_writer(0x3006).is_none());
+    }
+}
@@ -0,0 +1,123 @@
+use super::device::{Device, DeviceContext, Interrupt};
+use super::error::Fault;
+use std::ops::RangeInclusive;
+
+pub const KEYBOARD_STATUS_REGISTER: u16 = 0xFE00;
+pub const KEYBOARD_DATA_REGISTER: u16 = 0xFE02;
+
+/// Bit of the keyboard status register set while a key waits to be read
+const KEYBOARD_READY: u16 = 1 << 15;
+/// Bit of the keyboard status register that enables keyboard interrupts
+const KEYBOARD_INTERRUPT_ENABLE: u16 = 1 << 14;
+/// Entry of the interrupt vector table for the keyboard
+const KEYBOARD_INTERRUPT_VECTOR: u8 = 0x80;
+/// Priority level keyboard interrupts are raised at
+const KEYBOARD_PRIORITY: u16 = 4;
+
+/// The keyboard, reading keys from the console through its status (KBSR) and
+/// data (KBDR) registers.
+#[derive(Debug, Default)]
+pub struct Keyboard {
+    status: u16,
+    data: u16,
+}
+
+impl Keyboard {
+    pub fn new() -> Keyboard {
+        Keyboard::default()
+    }
+
+    /// Reads a key from the console into the data register, unless one is
+    /// already waiting there.
+    fn poll(&mut self, context: &mut DeviceContext, stop_at_end: bool) -> Result<(), Fault> {
+        if self.status & KEYBOARD_READY != 0 || !context.console().poll_key()? {
+            return Ok(());
+        }
+
+        match context.console().read_byte()? {
+            Some(key) => {
+                self.status |= KEYBOARD_READY;
+                self.data = key as u16;
+            }
+            None => context.set_input_exhausted(stop_at_end),
+        }
+
+        Ok(())
+    }
+}
+
+impl Device for Keyboard {
+    fn addresses(&self) -> RangeInclusive<u16> {
+        KEYBOARD_STATUS_REGISTER..=KEYBOARD_DATA_REGISTER + 1
+    }
+
+    fn read(&mut self, address: u16, context: &mut DeviceContext) -> Result<u16, Fault> {
+        // The way the keyboard status and data registers would be used normally
+        // is: whenever the user presses a key, the keyboard
+        // sets the status register's highest bit to one and the value of the
+        // key pressed into the data register; but that's not what MY keyboard
+        // will actually do when I press a button, so I have to emulate it.
+
+        // The way we do it is the following: when the executing program wants to read
+        // the status register, we check if a key has been pressed in the past; if it has, we set
+        // the status register's highest bit to one and its value to the data register.
+        // The key stays there until the program reads the data register.
+        match address {
+            KEYBOARD_STATUS_REGISTER => {
+                self.poll(context, true)?;
+                Ok(self.status)
+            }
+            KEYBOARD_DATA_REGISTER => {
+                self.status &= !KEYBOARD_READY;
+                Ok(self.data)
+            }
+            _ => Ok(0),
+        }
+    }
+
+    fn peek(&self, address: u16) -> u16 {
+        match address {
+            KEYBOARD_STATUS_REGISTER => self.status,
+            KEYBOARD_DATA_REGISTER => self.data,
+            _ => 0,
+        }
+    }
+
+    fn write(
+        &mut self,
+        address: u16,
+        value: u16,
+        _context: &mut DeviceContext,
+    ) -> Result<(), Fault> {
+        // Only the interrupt enable bit of the status register can be
+        // written, the ready bit and the data belong to the keyboard.
+        if address == KEYBOARD_STATUS_REGISTER {
+            self.status =
+                (self.status & !KEYBOARD_INTERRUPT_ENABLE) | (value & KEYBOARD_INTERRUPT_ENABLE);
+        }
+
+        Ok(())
+    }
+
+    fn tick(&mut self, context: &mut DeviceContext) -> Result<Option<Interrupt>, Fault> {
+        if self.status & KEYBOARD_INTERRUPT_ENABLE == 0 {
+            return Ok(None);
+        }
+
+        // Unlike a program polling the status register, an interrupt driven
+        // one is not waiting on the keyboard, so running out of input does
+        // not stop it.
+        if !context.input_exhausted() {
+            self.poll(context, false)?;
+        }
+
+        if self.status & KEYBOARD_READY != 0 {
+            Ok(Some(Interrupt {
+                vector: KEYBOARD_INTERRUPT_VECTOR,
+                priority: KEYBOARD_PRIORITY,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+}
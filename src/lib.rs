@@ -0,0 +1,42 @@
+//! A basic implementation of an [LC-3](https://en.wikipedia.org/wiki/Little_Computer_3) VM.
+//!
+//! The [`Vm`] type is the entry point for embedding the VM in other tools: load
+//! one or more object files into it and run them. The [`disassembler`] module
+//! turns LC-3 machine code back into a readable listing, and the [`console`]
+//! module decides where the programs' input comes from and output goes to.
+//!
+//! ```no_run
+//! let mut vm = lc3::Vm::new();
+//! vm.load_image_file("./examples/hello_world.obj").unwrap();
+//! vm.run().unwrap();
+//! ```
+
+pub mod callstack;
+pub mod console;
+pub mod dap;
+pub mod debugger;
+pub mod device;
+pub mod disassembler;
+pub mod display;
+mod error;
+pub mod gdbserver;
+pub mod history;
+pub mod keyboard;
+pub mod opcode;
+pub mod os;
+pub mod profile;
+pub mod state;
+pub mod symbols;
+pub mod timer;
+pub mod trace;
+pub mod tracediff;
+pub mod tui;
+mod vm;
+pub mod watchpoint;
+
+pub use error::{Fault, ImageError, Snapshot, VmError};
+pub use state::{Privilege, State};
+pub use vm::{
+    load_image_file, ExceptionMode, Limit, RunOptions, RunOutcome, StepOutcome, StopReason,
+    TrapMode, Vm, PC_START,
+};
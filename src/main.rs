@@ -1,16 +1,32 @@
+use lc3::console::{Console, MemoryConsole, TerminalConsole};
+use lc3::dap::DapServer;
+use lc3::debugger::{Command, Debugger};
+use lc3::gdbserver::GdbServer;
+use lc3::profile::Profiler;
+use lc3::symbols::SymbolTable;
+use lc3::trace::{TraceFormat, TraceReader, TraceRecord, Tracer};
+use lc3::tracediff::{self, Alignment, Truncated};
+use lc3::tui::Tui;
+use lc3::{disassembler, load_image_file, Limit, Privilege, RunOptions, StopReason, Vm};
 use nix::sys::signal;
 use std::env;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 use termios::*;
 
-mod state;
-use state::State;
+/// Port the GDB server listens on when no `--port` is given.
+const DEFAULT_GDB_PORT: u64 = 1234;
+/// How many instructions `trace-diff` runs object files for when no
+/// `--max-instructions` is given.
+const DEFAULT_TRACE_DIFF_STEPS: u64 = 10_000_000;
+/// How many of the hottest instructions the `--profile` report lists.
+const PROFILE_HOTTEST: usize = 20;
 
-mod opcode;
-use opcode::Opcode;
-
-mod disassembler;
-
-const PC_START: u16 = 0x3000;
+/// Pauses the program running in the debugger, set on SIGINT.
+static DEBUGGER_PAUSE: OnceLock<Arc<AtomicBool>> = OnceLock::new();
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -18,103 +34,438 @@ fn main() {
     if args.len() < 2 {
         println!("Usage:");
         println!("lc3 [image-file1] [image-file2] ... to run object files.");
+        println!("lc3 --os [image-file1] [image-file2] ... to run them on top of the bundled OS.");
+        println!("lc3 --user-mode [image-file1] ... to run them in user mode rather than supervisor mode.");
+        println!("lc3 --max-instructions N --max-output N --timeout SECONDS [image-file1] ... to stop runaway programs.");
+        println!("lc3 run --trace FILE [--trace-format text|binary] [image-file1] ... to trace every instruction.");
+        println!(
+            "lc3 run --profile [image-file1] ... to report where the program spends its time."
+        );
+        println!("lc3 run --profile-folded FILE [--symbols FILE.sym] [image-file1] ... to write call stacks for flame graphs.");
         println!("lc3 --disassemble [image-file1] [image-file2] ...  to disassemble them.");
+        println!(
+            "lc3 debug [--os] [image-file1] [image-file2] ... to step through them in a debugger."
+        );
+        println!("lc3 tui [--os] [image-file1] [image-file2] ... to debug them full-screen.");
+        println!("lc3 gdbserver [--port PORT] [--os] [image-file1] ... to debug them from GDB, on port {} by default.", DEFAULT_GDB_PORT);
+        println!("lc3 trace-diff [--stores] [--os] [--input FILE] expected actual to find where two traces or object files diverge.");
+        println!("lc3 dap to debug from an editor over the Debug Adapter Protocol.");
         std::process::exit(0);
     }
 
     if args[1] == "--disassemble" {
         for file_path in &args[2..] {
-            let buffer = load_image_file(file_path).unwrap();
-
-            let origin = buffer[0];
+            let buffer = load_image_file(file_path).unwrap_or_else(|error| {
+                eprintln!("Could not read {}: {}", file_path, error);
+                std::process::exit(1);
+            });
 
-            disassembler::disassemble(&buffer[1..].to_vec(), origin);
+            if let Some((origin, code)) = buffer.split_first() {
+                disassembler::disassemble(code, *origin);
+            }
         }
 
         std::process::exit(0);
+    } else if args[1] == "dap" {
+        serve_dap();
+    } else if args[1] == "trace-diff" {
+        trace_diff(&args[2..]);
     } else {
-        let mut state = State::new();
-        for file_path in &args[1..] {
-            let buffer = load_image_file(file_path).unwrap();
+        let mut vm = Vm::new();
+        let mut options = RunOptions::default();
+        let mut timeout = None;
+        let mut port = None;
+        let mut trace = None;
+        let mut trace_format = TraceFormat::Text;
+        let mut profile = Profile::default();
+        let mode = args[1].as_str();
+        let mut file_paths = match mode {
+            "run" | "debug" | "gdbserver" | "tui" => &args[2..],
+            _ => &args[1..],
+        };
+
+        while let Some(flag) = file_paths.first().filter(|arg| arg.starts_with("--")) {
+            if flag == "--os" {
+                vm.load_os();
+                file_paths = &file_paths[1..];
+                continue;
+            }
+            if flag == "--user-mode" {
+                vm.state_mut().set_privilege(Privilege::User);
+                file_paths = &file_paths[1..];
+                continue;
+            }
+            if flag == "--profile" {
+                profile.report = true;
+                file_paths = &file_paths[1..];
+                continue;
+            }
+            if ["--trace", "--trace-format", "--profile-folded", "--symbols"]
+                .contains(&flag.as_str())
+            {
+                let Some(value) = file_paths.get(1) else {
+                    eprintln!("{} needs a value", flag);
+                    std::process::exit(1);
+                };
+                match (flag.as_str(), value.as_str()) {
+                    ("--trace", path) => trace = Some(path.to_string()),
+                    ("--profile-folded", path) => profile.folded = Some(path.to_string()),
+                    ("--symbols", path) => match SymbolTable::load(path) {
+                        Ok(symbols) => profile.symbols.extend(&symbols),
+                        Err(error) => {
+                            eprintln!("Could not read {}: {}", path, error);
+                            std::process::exit(1);
+                        }
+                    },
+                    (_, "text") => trace_format = TraceFormat::Text,
+                    (_, "binary") => trace_format = TraceFormat::Binary,
+                    (_, format) => {
+                        eprintln!("Unknown trace format {}, use text or binary", format);
+                        std::process::exit(1);
+                    }
+                }
+                file_paths = &file_paths[2..];
+                continue;
+            }
+
+            let limit = match flag.as_str() {
+                "--max-instructions" => &mut options.max_instructions,
+                "--max-output" => &mut options.max_output_bytes,
+                "--timeout" => &mut timeout,
+                "--port" if mode == "gdbserver" => &mut port,
+                _ => {
+                    eprintln!("Unknown option {}", flag);
+                    std::process::exit(1);
+                }
+            };
+            *limit = file_paths.get(1).and_then(|value| value.parse().ok());
+            if limit.is_none() {
+                eprintln!("{} needs a number", flag);
+                std::process::exit(1);
+            }
+            file_paths = &file_paths[2..];
+        }
+
+        for file_path in file_paths {
+            if let Err(error) = vm.load_image_file(file_path) {
+                eprintln!("Could not load {}: {}", file_path, error);
+                std::process::exit(1);
+            }
+        }
+
+        if let Some(path) = trace {
+            let tracer = File::create(&path)
+                .and_then(|file| Tracer::new(Box::new(BufWriter::new(file)), trace_format));
+            match tracer {
+                Ok(tracer) => vm.set_tracer(tracer),
+                Err(error) => {
+                    eprintln!("Could not create {}: {}", path, error);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        if profile.report || profile.folded.is_some() {
+            vm.set_profiler(Profiler::new());
+        }
+
+        // The debuggers run the program at the user's pace, with nothing to
+        // enforce the limits.
+        let limited = options.max_instructions.is_some()
+            || options.max_output_bytes.is_some()
+            || timeout.is_some();
+        if limited && ["debug", "tui", "gdbserver"].contains(&mode) {
+            eprintln!(
+                "--max-instructions, --max-output and --timeout only apply to runs, not to lc3 {}",
+                mode
+            );
+            std::process::exit(1);
+        }
+
+        options.max_duration = timeout.map(Duration::from_secs);
+        match mode {
+            "debug" => run_debugger(vm),
+            "tui" => run_tui(vm),
+            "gdbserver" => serve_gdb(vm, port.unwrap_or(DEFAULT_GDB_PORT)),
+            _ => execute(vm, &options, &profile),
+        }
+    }
+}
 
-            // Read the first two bytes to figure out the origin, then put the
-            // rest of the file in state.memory starting from said origin.
-            let origin = buffer[0];
+/// What to make of the profiler's counts after a run.
+#[derive(Default)]
+struct Profile {
+    /// Whether to print the report
+    report: bool,
+    /// Where to write the folded call stacks
+    folded: Option<String>,
+    symbols: SymbolTable,
+}
 
-            let _ = &state.memory[origin as usize..origin as usize + buffer.len() - 1]
-                .copy_from_slice(&buffer[1..]);
+fn execute(vm: Vm, options: &RunOptions, profile: &Profile) {
+    // When stdin is not a terminal (piped input, CI containers) there is no
+    // line buffering or echo to turn off, so the program just runs headless.
+    let interactive = nix::unistd::isatty(0).unwrap_or(false);
+
+    if interactive {
+        let sig_action = signal::SigAction::new(
+            signal::SigHandler::Handler(handle_interrupt),
+            signal::SaFlags::empty(),
+            signal::SigSet::empty(),
+        );
+
+        disable_input_buffering();
+        unsafe {
+            signal::sigaction(signal::Signal::SIGINT, &sig_action).unwrap();
         }
+    }
 
-        execute(&mut state);
+    let outcome = vm.run_with(options);
+
+    if interactive {
+        restore_input_buffering();
+    }
+
+    if let Some(error) = outcome.trace_error {
+        eprintln!("Could not write the trace: {}", error);
+        std::process::exit(1);
+    }
+    if let Some(profiler) = &outcome.profiler {
+        if profile.report {
+            eprint!(
+                "\n{}",
+                profiler.report(&outcome.state.memory, PROFILE_HOTTEST, &profile.symbols)
+            );
+        }
+        if let Some(path) = &profile.folded {
+            let written = File::create(path).and_then(|file| {
+                let mut output = BufWriter::new(file);
+                profiler.write_folded(&mut output, &profile.symbols)?;
+                output.flush()
+            });
+            if let Err(error) = written {
+                eprintln!("Could not write {}: {}", path, error);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    match outcome.reason {
+        StopReason::Halted | StopReason::EndOfInput => {}
+        StopReason::LimitReached(limit) => {
+            let limit = match limit {
+                Limit::Instructions => "instruction limit",
+                Limit::OutputBytes => "output limit",
+                Limit::Duration => "timeout",
+            };
+            eprintln!(
+                "\nStopped after {} instructions: {} reached",
+                outcome.instructions, limit
+            );
+            std::process::exit(3);
+        }
+        StopReason::Faulted(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
     }
 }
 
-fn execute(state: &mut State) {
+/// Reads debugger commands from stdin until `quit` or the end of the input.
+/// An empty line repeats the previous command, and Ctrl-C pauses the program
+/// instead of ending the session.
+fn run_debugger(vm: Vm) {
+    let mut debugger = Debugger::new(vm);
+    let mut input = TerminalConsole::new();
+    let mut previous: Option<Command> = None;
+
+    let _ = DEBUGGER_PAUSE.set(debugger.pause_flag());
     let sig_action = signal::SigAction::new(
-        signal::SigHandler::Handler(handle_interrupt),
-        signal::SaFlags::empty(),
+        signal::SigHandler::Handler(pause_debugger),
+        signal::SaFlags::SA_RESTART,
         signal::SigSet::empty(),
     );
-
-    disable_input_buffering();
     unsafe {
         signal::sigaction(signal::Signal::SIGINT, &sig_action).unwrap();
     }
 
-    state.pc = PC_START;
-    while state.running {
-        let instruction = state.mem_read(state.pc);
-
-        let opcode = instruction >> 12;
-        state.pc += 1;
-
-        match opcode.try_into() {
-            Ok(Opcode::BR) => state.conditional_branch(instruction),
-            Ok(Opcode::ADD) => state.add(instruction),
-            Ok(Opcode::LD) => state.load(instruction),
-            Ok(Opcode::ST) => state.store(instruction),
-            Ok(Opcode::JSR) => state.jump_to_subroutine(instruction),
-            Ok(Opcode::AND) => state.and(instruction),
-            Ok(Opcode::LDR) => state.load_base_plus_offset(instruction),
-            Ok(Opcode::STR) => state.store_base_plus_offset(instruction),
-            Ok(Opcode::RTI) => {
-                unimplemented!()
-            }
-            Ok(Opcode::NOT) => state.not(instruction),
-            Ok(Opcode::LDI) => state.load_indirect(instruction),
-            Ok(Opcode::STI) => state.store_indirect(instruction),
-            Ok(Opcode::JMP) => state.jump(instruction),
-            Ok(Opcode::RES) => state.illegal_opcode(),
-            Ok(Opcode::LEA) => state.load_effective_address(instruction),
-            Ok(Opcode::TRAP) => state.trap(instruction),
-            Err(_) => {
-                panic!(
-                    "Unknown Opcode: {}\nRegisters: {:?}\nPc: {:x}\nZF: {}\nNF: {}\nPF: {}\n",
-                    opcode,
-                    state.registers,
-                    state.pc,
-                    (state.cflags >> 1) & 0x1,
-                    (state.cflags >> 2) & 0x1,
-                    state.cflags & 0x1
-                );
+    println!("Type help for the list of commands.");
+    println!(
+        "{}",
+        debugger.execute(&Command::List {
+            start: Some(debugger.vm().state().pc),
+            count: 1
+        })
+    );
+
+    loop {
+        print!("(lc3) ");
+        let _ = std::io::stdout().flush();
+
+        let line = match read_command_line(&mut input) {
+            Some(line) => line,
+            None => {
+                println!();
+                break;
             }
+        };
+
+        let command = match &previous {
+            Some(command) if line.trim().is_empty() => Ok(command.clone()),
+            _ => Command::parse(&line),
+        };
+
+        match command {
+            Ok(Command::Quit) => break,
+            Ok(command) => {
+                println!("{}", debugger.execute(&command));
+                previous = Some(command);
+            }
+            Err(error) => println!("{}", error),
         }
     }
+}
 
-    restore_input_buffering();
+/// Reads a line typed at the debugger prompt. It goes through the same
+/// unbuffered reads as the program's own input, so that neither of them can
+/// swallow what was typed for the other. Returns `None` at the end of the
+/// input.
+fn read_command_line(input: &mut TerminalConsole) -> Option<String> {
+    let mut line = Vec::new();
+
+    loop {
+        match input.read_byte() {
+            Ok(Some(b'\n')) => break,
+            Ok(Some(byte)) => line.push(byte),
+            Ok(None) | Err(_) if line.is_empty() => return None,
+            Ok(None) | Err(_) => break,
+        }
+    }
+
+    Some(String::from_utf8_lossy(&line).into_owned())
+}
+
+extern "C" fn pause_debugger(_: i32) {
+    if let Some(pause) = DEBUGGER_PAUSE.get() {
+        pause.store(true, Ordering::Relaxed);
+    }
+}
+
+fn run_tui(vm: Vm) {
+    if let Err(error) = Tui::new(vm).run() {
+        eprintln!("Terminal UI failed: {}", error);
+        std::process::exit(1);
+    }
+}
+
+fn serve_gdb(vm: Vm, port: u64) {
+    let Ok(port) = u16::try_from(port) else {
+        eprintln!("Invalid port {}", port);
+        std::process::exit(1);
+    };
+
+    println!("Waiting for GDB on localhost:{}", port);
+    if let Err(error) = GdbServer::new(vm).listen(port) {
+        eprintln!("GDB connection failed: {}", error);
+        std::process::exit(1);
+    }
 }
 
-// Every single value needs to be swapped to account for big endianness
-fn load_image_file(file_path: &str) -> Result<Vec<u16>, std::io::Error> {
-    let contents = std::fs::read(file_path)?;
+/// Compares two executions, each given as a trace or an object file to run on
+/// the same input. Exits with code 4 if they diverge.
+fn trace_diff(mut args: &[String]) {
+    let mut alignment = Alignment::Steps;
+    let mut os = false;
+    let mut input = Vec::new();
+    let mut max_steps = DEFAULT_TRACE_DIFF_STEPS;
 
-    let buffer: Vec<u16> = contents
-        .chunks_exact(2)
-        .into_iter()
-        .map(|a| u16::from_be_bytes([a[0], a[1]]))
-        .collect();
+    while let Some(flag) = args.first().filter(|arg| arg.starts_with("--")) {
+        let value = args.get(1);
+        args = &args[1..];
+
+        match (flag.as_str(), value) {
+            ("--stores", _) => alignment = Alignment::Stores,
+            ("--os", _) => os = true,
+            ("--input", Some(path)) => {
+                input = std::fs::read(path).unwrap_or_else(|error| {
+                    eprintln!("Could not read {}: {}", path, error);
+                    std::process::exit(1);
+                });
+                args = &args[1..];
+            }
+            ("--max-instructions", Some(value)) => {
+                max_steps = value.parse().unwrap_or_else(|_| {
+                    eprintln!("--max-instructions needs a number");
+                    std::process::exit(1);
+                });
+                args = &args[1..];
+            }
+            _ => {
+                eprintln!("Unknown or incomplete option {}", flag);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let [expected, actual] = args else {
+        eprintln!("trace-diff compares exactly two traces or object files");
+        std::process::exit(1);
+    };
+    let open = |path: &String| -> Box<dyn Iterator<Item = std::io::Result<TraceRecord>>> {
+        let execution = if path.ends_with(".obj") {
+            let mut vm = Vm::with_console(Box::new(MemoryConsole::new(&input)));
+            if os {
+                vm.load_os();
+            }
+            vm.load_image_file(path)
+                .map(|_| -> Box<dyn Iterator<Item = _>> {
+                    Box::new(tracediff::execute(vm, Some(max_steps)))
+                })
+                .map_err(|error| error.to_string())
+        } else {
+            File::open(path)
+                .and_then(|file| TraceReader::new(BufReader::new(file)))
+                .map(|reader| -> Box<dyn Iterator<Item = _>> { Box::new(reader) })
+                .map_err(|error| error.to_string())
+        };
 
-    Ok(buffer)
+        execution.unwrap_or_else(|error| {
+            eprintln!("Could not load {}: {}", path, error);
+            std::process::exit(1);
+        })
+    };
+
+    match tracediff::diff(open(expected), open(actual), alignment) {
+        Ok(None) => println!("The executions match"),
+        Ok(Some(divergence)) => {
+            print!("{}", divergence);
+            std::process::exit(4);
+        }
+        Err(error) => match Truncated::from_error(&error) {
+            Some(truncated) => {
+                eprintln!(
+                    "The executions match until {}, raise --max-instructions to compare further",
+                    truncated
+                );
+                std::process::exit(3);
+            }
+            None => {
+                eprintln!("Could not read the traces: {}", error);
+                std::process::exit(1);
+            }
+        },
+    }
+}
+
+/// Speaks the Debug Adapter Protocol over stdin and stdout, which leaves
+/// stderr as the only place for errors.
+fn serve_dap() {
+    let input = std::io::BufReader::new(std::io::stdin());
+
+    if let Err(error) = DapServer::new(std::io::stdout()).serve(input) {
+        eprintln!("Debug adapter failed: {}", error);
+        std::process::exit(1);
+    }
 }
 
 fn disable_input_buffering() {
@@ -125,7 +476,7 @@ fn disable_input_buffering() {
 
 extern "C" fn handle_interrupt(_: i32) {
     restore_input_buffering();
-    println!("");
+    println!();
     std::process::exit(2);
 }
 
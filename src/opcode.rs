@@ -1,5 +1,8 @@
+//! Instruction opcodes and trap vectors, named after their assembly mnemonics.
+
 use std::convert::TryFrom;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub enum Opcode {
     BR,
@@ -20,6 +23,8 @@ pub enum Opcode {
     TRAP,
 }
 
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug)]
 pub enum Trapcode {
     /// Get character from keyboard, not echoed into the terminal
     GETC = 0x20,
@@ -0,0 +1,312 @@
+//! A small LC-3 operating system, in the spirit of the one shipped with the
+//! textbook simulator: trap service routines written in LC-3 code, handlers
+//! for the exceptions, and the trap and interrupt vector tables pointing at
+//! them. Load it with [`crate::Vm::load_os`].
+//!
+//! The routines run in supervisor mode and return to the caller's mode with
+//! RTI. They talk to the keyboard and display through their device registers,
+//! and `HALT` stops the machine by clearing the clock enable bit of the
+//! machine control register.
+
+/// Where the trap vector table starts; it is also the origin of the image
+const TRAP_VECTOR_TABLE: u16 = 0x0000;
+/// Number of entries in each of the vector tables
+const VECTOR_TABLE_SIZE: u16 = 0x100;
+
+// Entry points of the routines in OS_CODE
+const TRAP_GETC: u16 = 0x0200;
+const TRAP_OUT: u16 = 0x0204;
+const TRAP_PUTS: u16 = 0x020A;
+const TRAP_IN: u16 = 0x0217;
+const TRAP_PUTSP: u16 = 0x0223;
+const TRAP_HALT: u16 = 0x0244;
+const BAD_TRAP: u16 = 0x024B;
+const PRIVILEGE_VIOLATION: u16 = 0x024E;
+const ILLEGAL_OPCODE: u16 = 0x0251;
+const ACCESS_VIOLATION: u16 = 0x0254;
+const BAD_INTERRUPT: u16 = 0x0257;
+
+// The routines, assembled to start at 0x0200 right after the interrupt vector
+// table. Every word is annotated with the assembly it comes from; strings are
+// stored one character per word.
+const OS_CODE: [u16; 295] = [
+    0xA057, // TRAP_GETC: LDI R0, OS_KBSR. Wait until a key is pressed
+    0x07FE, // BRzp TRAP_GETC
+    0xA056, // LDI R0, OS_KBDR. Read it into R0
+    0x8000, // RTI
+    0x325B, // TRAP_OUT: ST R1, OUT_SAVE_R1
+    0xA254, // OUT_WAIT: LDI R1, OS_DSR. Wait until the display is ready
+    0x07FE, // BRzp OUT_WAIT
+    0xB053, // STI R0, OS_DDR. Write the character in R0
+    0x2257, // LD R1, OUT_SAVE_R1
+    0x8000, // RTI
+    0x3056, // TRAP_PUTS: ST R0, PUTS_SAVE_R0
+    0x3256, // ST R1, PUTS_SAVE_R1
+    0x3E56, // ST R7, PUTS_SAVE_R7
+    0x1220, // ADD R1, R0, #0. R1 walks the string
+    0x6040, // PUTS_LOOP: LDR R0, R1, #0
+    0x0403, // BRz PUTS_DONE. Stop at the null terminator
+    0xF021, // OUT
+    0x1261, // ADD R1, R1, #1
+    0x0FFB, // BRnzp PUTS_LOOP
+    0x204D, // PUTS_DONE: LD R0, PUTS_SAVE_R0
+    0x224D, // LD R1, PUTS_SAVE_R1
+    0x2E4D, // LD R7, PUTS_SAVE_R7
+    0x8000, // RTI
+    0x3E4D, // TRAP_IN: ST R7, IN_SAVE_R7
+    0xE053, // LEA R0, IN_PROMPT. Prompt for a character
+    0xF022, // PUTS
+    0xF020, // GETC
+    0xF021, // OUT. Echo it
+    0x3047, // ST R0, IN_SAVE_R0
+    0x5020, // AND R0, R0, #0. Followed by a line feed
+    0x102A, // ADD R0, R0, #10
+    0xF021, // OUT
+    0x2043, // LD R0, IN_SAVE_R0
+    0x2E43, // LD R7, IN_SAVE_R7
+    0x8000, // RTI
+    0x3042, // TRAP_PUTSP: ST R0, PUTSP_SAVE_R0
+    0x3242, // ST R1, PUTSP_SAVE_R1
+    0x3442, // ST R2, PUTSP_SAVE_R2
+    0x3642, // ST R3, PUTSP_SAVE_R3
+    0x3842, // ST R4, PUTSP_SAVE_R4
+    0x3E42, // ST R7, PUTSP_SAVE_R7
+    0x1220, // ADD R1, R0, #0. R1 walks the string
+    0x6440, // PUTSP_LOOP: LDR R2, R1, #0. R2 holds the next two characters
+    0x2631, // LD R3, LOW_BYTE
+    0x5083, // AND R0, R2, R3. The first one is in the low byte
+    0x040F, // BRz PUTSP_DONE
+    0xF021, // OUT
+    0x5020, // AND R0, R0, #0. There is no right shift, so build the high byte bit by bit
+    0x1621, // ADD R3, R0, #1. R3 is the bit of the result
+    0x282C, // LD R4, HIGH_BYTE_START. R4 is the bit of the word
+    0x5E84, // PUTSP_SHIFT: AND R7, R2, R4
+    0x0401, // BRz PUTSP_NEXT_BIT
+    0x1003, // ADD R0, R0, R3
+    0x16C3, // PUTSP_NEXT_BIT: ADD R3, R3, R3
+    0x1904, // ADD R4, R4, R4
+    0x0BFA, // BRnp PUTSP_SHIFT. Until the bit shifts out of the word
+    0x1020, // ADD R0, R0, #0
+    0x0403, // BRz PUTSP_DONE
+    0xF021, // OUT
+    0x1261, // ADD R1, R1, #1
+    0x0FED, // BRnzp PUTSP_LOOP
+    0x2028, // PUTSP_DONE: LD R0, PUTSP_SAVE_R0
+    0x2228, // LD R1, PUTSP_SAVE_R1
+    0x2428, // LD R2, PUTSP_SAVE_R2
+    0x2628, // LD R3, PUTSP_SAVE_R3
+    0x2828, // LD R4, PUTSP_SAVE_R4
+    0x2E28, // LD R7, PUTSP_SAVE_R7
+    0x8000, // RTI
+    0xE03C, // TRAP_HALT: LEA R0, HALT_MESSAGE
+    0xF022, // PUTS
+    0xA015, // LDI R0, OS_MCR. Clear the clock enable bit
+    0x2217, // LD R1, CLOCK_ENABLE_OFF
+    0x5001, // AND R0, R0, R1
+    0xB012, // STI R0, OS_MCR
+    0x0FF9, // BRnzp TRAP_HALT. Should the clock keep going, halt again
+    0xE052, // BAD_TRAP: LEA R0, BAD_TRAP_MESSAGE
+    0xF022, // PUTS
+    0xF025, // HALT
+    0xE073, // PRIVILEGE_VIOLATION: LEA R0, PRIVILEGE_VIOLATION_MESSAGE
+    0xF022, // PUTS
+    0xF025, // HALT
+    0xE095, // ILLEGAL_OPCODE: LEA R0, ILLEGAL_OPCODE_MESSAGE
+    0xF022, // PUTS
+    0xF025, // HALT
+    0xE0AD, // ACCESS_VIOLATION: LEA R0, ACCESS_VIOLATION_MESSAGE
+    0xF022, // PUTS
+    0xF025, // HALT
+    0x8000, // BAD_INTERRUPT: RTI
+    0xFE00, // OS_KBSR: .FILL xFE00
+    0xFE02, // OS_KBDR: .FILL xFE02
+    0xFE04, // OS_DSR: .FILL xFE04
+    0xFE06, // OS_DDR: .FILL xFE06
+    0xFFFE, // OS_MCR: .FILL xFFFE
+    0x00FF, // LOW_BYTE: .FILL x00FF
+    0x0100, // HIGH_BYTE_START: .FILL x0100
+    0x7FFF, // CLOCK_ENABLE_OFF: .FILL x7FFF
+    0x0000, // OUT_SAVE_R1: .FILL 0
+    0x0000, // PUTS_SAVE_R0: .FILL 0
+    0x0000, // PUTS_SAVE_R1: .FILL 0
+    0x0000, // PUTS_SAVE_R7: .FILL 0
+    0x0000, // IN_SAVE_R0: .FILL 0
+    0x0000, // IN_SAVE_R7: .FILL 0
+    0x0000, // PUTSP_SAVE_R0: .FILL 0
+    0x0000, // PUTSP_SAVE_R1: .FILL 0
+    0x0000, // PUTSP_SAVE_R2: .FILL 0
+    0x0000, // PUTSP_SAVE_R3: .FILL 0
+    0x0000, // PUTSP_SAVE_R4: .FILL 0
+    0x0000, // PUTSP_SAVE_R7: .FILL 0
+    // IN_PROMPT: .STRINGZ "\nInput a character> "
+    0x000A, 0x0049, 0x006E, 0x0070, 0x0075, 0x0074, 0x0020, 0x0061, 0x0020, 0x0063, 0x0068, 0x0061,
+    0x0072, 0x0061, 0x0063, 0x0074, 0x0065, 0x0072, 0x003E, 0x0020, 0x0000,
+    // HALT_MESSAGE: .STRINGZ "\n\n--- halting the LC-3 ---\n\n"
+    0x000A, 0x000A, 0x002D, 0x002D, 0x002D, 0x0020, 0x0068, 0x0061, 0x006C, 0x0074, 0x0069, 0x006E,
+    0x0067, 0x0020, 0x0074, 0x0068, 0x0065, 0x0020, 0x004C, 0x0043, 0x002D, 0x0033, 0x0020, 0x002D,
+    0x002D, 0x002D, 0x000A, 0x000A, 0x0000,
+    // BAD_TRAP_MESSAGE: .STRINGZ "\n\n--- undefined trap executed ---\n\n"
+    0x000A, 0x000A, 0x002D, 0x002D, 0x002D, 0x0020, 0x0075, 0x006E, 0x0064, 0x0065, 0x0066, 0x0069,
+    0x006E, 0x0065, 0x0064, 0x0020, 0x0074, 0x0072, 0x0061, 0x0070, 0x0020, 0x0065, 0x0078, 0x0065,
+    0x0063, 0x0075, 0x0074, 0x0065, 0x0064, 0x0020, 0x002D, 0x002D, 0x002D, 0x000A, 0x000A, 0x0000,
+    // PRIVILEGE_VIOLATION_MESSAGE: .STRINGZ "\n\n--- privilege mode violation ---\n\n"
+    0x000A, 0x000A, 0x002D, 0x002D, 0x002D, 0x0020, 0x0070, 0x0072, 0x0069, 0x0076, 0x0069, 0x006C,
+    0x0065, 0x0067, 0x0065, 0x0020, 0x006D, 0x006F, 0x0064, 0x0065, 0x0020, 0x0076, 0x0069, 0x006F,
+    0x006C, 0x0061, 0x0074, 0x0069, 0x006F, 0x006E, 0x0020, 0x002D, 0x002D, 0x002D, 0x000A, 0x000A,
+    0x0000, // ILLEGAL_OPCODE_MESSAGE: .STRINGZ "\n\n--- illegal opcode ---\n\n"
+    0x000A, 0x000A, 0x002D, 0x002D, 0x002D, 0x0020, 0x0069, 0x006C, 0x006C, 0x0065, 0x0067, 0x0061,
+    0x006C, 0x0020, 0x006F, 0x0070, 0x0063, 0x006F, 0x0064, 0x0065, 0x0020, 0x002D, 0x002D, 0x002D,
+    0x000A, 0x000A, 0x0000,
+    // ACCESS_VIOLATION_MESSAGE: .STRINGZ "\n\n--- access control violation ---\n\n"
+    0x000A, 0x000A, 0x002D, 0x002D, 0x002D, 0x0020, 0x0061, 0x0063, 0x0063, 0x0065, 0x0073, 0x0073,
+    0x0020, 0x0063, 0x006F, 0x006E, 0x0074, 0x0072, 0x006F, 0x006C, 0x0020, 0x0076, 0x0069, 0x006F,
+    0x006C, 0x0061, 0x0074, 0x0069, 0x006F, 0x006E, 0x0020, 0x002D, 0x002D, 0x002D, 0x000A, 0x000A,
+    0x0000,
+];
+
+/// The operating system image, ready for [`crate::Vm::load_image`]: its
+/// origin followed by the trap vector table, the interrupt vector table and
+/// the code.
+pub fn image() -> Vec<u16> {
+    let trap_vector_table = (0..VECTOR_TABLE_SIZE).map(|vector| match vector {
+        0x20 => TRAP_GETC,
+        0x21 => TRAP_OUT,
+        0x22 => TRAP_PUTS,
+        0x23 => TRAP_IN,
+        0x24 => TRAP_PUTSP,
+        0x25 => TRAP_HALT,
+        _ => BAD_TRAP,
+    });
+    let interrupt_vector_table = (0..VECTOR_TABLE_SIZE).map(|vector| match vector {
+        0x00 => PRIVILEGE_VIOLATION,
+        0x01 => ILLEGAL_OPCODE,
+        0x02 => ACCESS_VIOLATION,
+        _ => BAD_INTERRUPT,
+    });
+
+    std::iter::once(TRAP_VECTOR_TABLE)
+        .chain(trap_vector_table)
+        .chain(interrupt_vector_table)
+        .chain(OS_CODE)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::console::MemoryConsole;
+    use crate::{Privilege, Vm};
+
+    #[test]
+    fn serves_traps_for_user_mode_programs() {
+        let console = MemoryConsole::new(b"x");
+        let mut vm = Vm::with_console(Box::new(console.clone()));
+        vm.load_os();
+        vm.load_image(&[
+            0x3000, // origin
+            0x200B, // LD R0, USER_PSR. Push the user mode PSR and PC
+            0x1DBF, // ADD R6, R6, #-1
+            0x7180, // STR R0, R6, #0
+            0xE003, // LEA R0, USER
+            0x1DBF, // ADD R6, R6, #-1
+            0x7180, // STR R0, R6, #0
+            0x8000, // RTI. Drop into user mode
+            0xE005, // USER: LEA R0, MESSAGE
+            0xF022, // PUTS
+            0xF020, // GETC
+            0xF021, // OUT
+            0xF025, // HALT
+            0x8002, // USER_PSR: .FILL x8002
+            0x0048, 0x0069, 0x0000, // MESSAGE: .STRINGZ "Hi"
+        ])
+        .unwrap();
+
+        let mut traps = 0;
+        while vm.state().running() {
+            let pc = vm.state().pc;
+            vm.step();
+            if (0x3008..=0x300B).contains(&pc) {
+                assert_eq!(vm.state().privilege(), Privilege::Supervisor);
+                traps += 1;
+            }
+            if [0x3009, 0x300A, 0x300B].contains(&vm.state().pc) {
+                assert_eq!(vm.state().privilege(), Privilege::User);
+            }
+        }
+
+        assert_eq!(traps, 4);
+        assert_eq!(
+            String::from_utf8(console.output()).unwrap(),
+            "Hix\n\n--- halting the LC-3 ---\n\n"
+        );
+    }
+
+    /// Runs `program` at 0x3000 on top of the OS until it halts, returning
+    /// what it printed and the machine.
+    fn run(input: &[u8], program: &[u16]) -> (String, Vm) {
+        let console = MemoryConsole::new(input);
+        let mut vm = Vm::with_console(Box::new(console.clone()));
+        vm.load_os();
+        let mut image = vec![0x3000];
+        image.extend(program);
+        vm.load_image(&image).unwrap();
+
+        while vm.state().running() {
+            vm.step();
+        }
+
+        (String::from_utf8(console.output()).unwrap(), vm)
+    }
+
+    #[test]
+    fn in_prompts_for_a_character_and_echoes_it() {
+        let (output, vm) = run(
+            b"k",
+            &[
+                0xF023, // IN
+                0x3001, // ST R0, KEY
+                0xF025, // HALT
+                0x0000, // KEY: .FILL 0
+            ],
+        );
+
+        assert_eq!(
+            output,
+            "\nInput a character> k\n\n\n--- halting the LC-3 ---\n\n"
+        );
+        assert_eq!(vm.state().memory[0x3003], b'k' as u16);
+    }
+
+    #[test]
+    fn putsp_prints_two_characters_per_word() {
+        let (output, _) = run(
+            b"",
+            &[
+                0xE002, // LEA R0, STRING
+                0xF024, // PUTSP
+                0xF025, // HALT
+                0x6548, // STRING: "He"
+                0x7279, // "yr"
+                0x0021, // "!", and the high byte ends the string
+                0x4141, // "AA", never printed
+            ],
+        );
+
+        assert_eq!(output, "Heyr!\n\n--- halting the LC-3 ---\n\n");
+    }
+
+    #[test]
+    fn putsp_stops_at_a_zero_low_byte() {
+        let (output, _) = run(
+            b"",
+            &[
+                0xE002, // LEA R0, STRING
+                0xF024, // PUTSP
+                0xF025, // HALT
+                0x6948, // STRING: "Hi"
+                0x4100, // a zero low byte ends the string
+            ],
+        );
+
+        assert_eq!(output, "Hi\n\n--- halting the LC-3 ---\n\n");
+    }
+}
@@ -0,0 +1,437 @@
+//! Counts where a program spends its time.
+//!
+//! A [`Profiler`] set with [`Vm::set_profiler`](crate::Vm::set_profiler) sees
+//! every step, and counts how many times each address executes, how many
+//! instructions are spent in each subroutine and how many loads and stores
+//! hit each region of memory. Subroutines are followed with the VM's
+//! [`CallStack`].
+//!
+//! Every instruction is also a sample of the call stack it ran in, and
+//! [`Profiler::write_folded`] writes them in the folded stack format, one
+//! line per stack with the frames from the outermost in, separated by
+//! semicolons, and the number of instructions:
+//!
+//! ```text
+//! START 44
+//! START;SQUARE 195
+//! START;0x0244;0x020A;0x0204 168
+//! ```
+//!
+//! which flame graph tools like `flamegraph.pl` and `inferno` draw. The
+//! outermost frame is where the program started.
+
+use super::callstack::{CallEvent, CallStack, Frame, MAX_DEPTH};
+use super::disassembler::disassemble_instruction;
+use super::state::MEMORY_SIZE;
+use super::symbols::SymbolTable;
+use super::vm::StepOutcome;
+use super::watchpoint::Access;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::ops::RangeInclusive;
+
+/// Regions of the memory map the loads and stores are counted in.
+pub const REGIONS: [(&str, RangeInclusive<u16>); 5] = [
+    ("trap vector table", 0x0000..=0x00FF),
+    ("interrupt vector table", 0x0100..=0x01FF),
+    ("operating system", 0x0200..=0x2FFF),
+    ("user programs", 0x3000..=0xFDFF),
+    ("device registers", 0xFE00..=0xFFFF),
+];
+
+/// What a subroutine cost.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SubroutineStats {
+    /// Times it was entered
+    pub calls: u64,
+    /// Instructions executed in it, not counting the subroutines it called
+    pub own_instructions: u64,
+    /// Instructions executed from entering it to leaving it. Recursive calls
+    /// are only counted once
+    pub total_instructions: u64,
+}
+
+/// Loads and stores of one of the [`REGIONS`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegionStats {
+    pub loads: u64,
+    pub stores: u64,
+}
+
+/// Collects the counts of a run.
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    /// Times each address was executed
+    executions: Vec<u64>,
+    /// Keyed by entry address
+    subroutines: BTreeMap<u16, SubroutineStats>,
+    /// Instructions executed outside of any subroutine
+    top_level_instructions: u64,
+    regions: [RegionStats; REGIONS.len()],
+    instructions: u64,
+    /// The frames entered since profiling started, outermost first, entered
+    /// at a count of `instructions`
+    frames: Vec<Frame>,
+    /// Address of the first instruction executed
+    start: Option<u16>,
+    /// Instructions executed in each stack, keyed by the entries of its
+    /// frames, outermost first
+    samples: BTreeMap<Vec<u16>, u64>,
+    /// The entries of the current stack, whose instructions are counted in
+    /// `pending` until it changes
+    path: Vec<u16>,
+    pending: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            executions: vec![0; MEMORY_SIZE],
+            subroutines: BTreeMap::new(),
+            top_level_instructions: 0,
+            regions: [RegionStats::default(); REGIONS.len()],
+            instructions: 0,
+            frames: Vec::new(),
+            start: None,
+            samples: BTreeMap::new(),
+            path: Vec::new(),
+            pending: 0,
+        }
+    }
+
+    /// Instructions executed so far.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Times the instruction at `address` was executed.
+    pub fn executions(&self, address: u16) -> u64 {
+        self.executions[address as usize]
+    }
+
+    /// Instructions executed outside of any subroutine.
+    pub fn top_level_instructions(&self) -> u64 {
+        self.top_level_instructions
+    }
+
+    /// The subroutines entered so far, keyed by entry address. The ones still
+    /// running count as if they returned now.
+    pub fn subroutines(&self) -> BTreeMap<u16, SubroutineStats> {
+        let mut subroutines = self.subroutines.clone();
+
+        let frames = &self.frames;
+        for (depth, frame) in frames.iter().enumerate() {
+            let outermost = !frames[..depth]
+                .iter()
+                .any(|caller| caller.entry == frame.entry);
+            if outermost {
+                let stats = subroutines.entry(frame.entry).or_default();
+                stats.total_instructions += self.instructions() - frame.entered_at;
+            }
+        }
+
+        subroutines
+    }
+
+    /// Loads and stores of each of the [`REGIONS`], in the same order.
+    pub fn regions(&self) -> &[RegionStats] {
+        &self.regions
+    }
+
+    /// Accounts for a step that executed the instruction at `pc` (or took an
+    /// interrupt) after making the memory `accesses`, leaving `call_stack`
+    /// changed by `event`.
+    pub(crate) fn record(
+        &mut self,
+        pc: u16,
+        outcome: &StepOutcome,
+        call_stack: &CallStack,
+        event: Option<CallEvent>,
+        accesses: &[(Access, u16)],
+    ) {
+        for (access, address) in accesses {
+            let region = REGIONS
+                .iter()
+                .position(|(_, addresses)| addresses.contains(address))
+                .expect("the regions cover the whole memory");
+            match access {
+                Access::Read => self.regions[region].loads += 1,
+                Access::Write => self.regions[region].stores += 1,
+            }
+        }
+
+        if !matches!(outcome, StepOutcome::Interrupted(_)) {
+            self.instructions += 1;
+            self.start.get_or_insert(pc);
+            self.executions[pc as usize] += 1;
+            self.pending += 1;
+            match self.path.last() {
+                Some(entry) => {
+                    let stats = self.subroutines.entry(*entry).or_default();
+                    stats.own_instructions += 1;
+                }
+                None => self.top_level_instructions += 1,
+            }
+        }
+
+        match event {
+            None => return,
+            Some(CallEvent::Entered) => {
+                let frame = *call_stack.frames().last().expect("a frame was entered");
+                self.subroutines.entry(frame.entry).or_default().calls += 1;
+                if self.frames.len() == MAX_DEPTH {
+                    self.frames.remove(0);
+                }
+                self.frames.push(Frame {
+                    entered_at: self.instructions,
+                    ..frame
+                });
+            }
+            // Frames entered before profiling started aren't timed
+            Some(CallEvent::Left(_)) => {
+                if let Some(frame) = self.frames.pop() {
+                    if !self.frames.iter().any(|caller| caller.entry == frame.entry) {
+                        let stats = self.subroutines.entry(frame.entry).or_default();
+                        stats.total_instructions += self.instructions - frame.entered_at;
+                    }
+                }
+            }
+        }
+
+        let path = call_stack
+            .frames()
+            .iter()
+            .map(|frame| frame.entry)
+            .collect();
+        let previous = std::mem::replace(&mut self.path, path);
+        if self.pending > 0 {
+            *self.samples.entry(previous).or_default() += self.pending;
+            self.pending = 0;
+        }
+    }
+
+    /// Writes the instructions executed in each call stack in the folded
+    /// stack format described in the [module docs](self), naming the frames
+    /// after the `symbols` of their entries.
+    pub fn write_folded(
+        &self,
+        output: &mut impl std::io::Write,
+        symbols: &SymbolTable,
+    ) -> std::io::Result<()> {
+        let Some(start) = self.start else {
+            return Ok(());
+        };
+        let root = symbols.describe(start);
+
+        let mut samples = self.samples.clone();
+        *samples.entry(self.path.clone()).or_default() += self.pending;
+
+        for (path, count) in samples {
+            if count == 0 {
+                continue;
+            }
+            let mut stack = root.clone();
+            for entry in path {
+                stack.push(';');
+                stack.push_str(&symbols.describe(entry));
+            }
+            writeln!(output, "{} {}", stack, count)?;
+        }
+
+        Ok(())
+    }
+
+    /// Describes the counts: the `hottest` instructions with their
+    /// disassembly from `memory`, the subroutines, named after the
+    /// `symbols` of their entries, and the memory accesses.
+    pub fn report(&self, memory: &[u16], hottest: usize, symbols: &SymbolTable) -> String {
+        let mut report = String::new();
+        let instructions = self.instructions();
+        let total = instructions.max(1) as f64;
+        let _ = writeln!(report, "{} instructions executed", instructions);
+
+        let mut addresses: Vec<usize> = (0..MEMORY_SIZE)
+            .filter(|address| self.executions[*address] > 0)
+            .collect();
+        addresses.sort_by_key(|address| Reverse(self.executions[*address]));
+
+        let _ = writeln!(report, "\nHottest instructions:");
+        let _ = writeln!(report, "      count       %  address  instruction");
+        for address in addresses.into_iter().take(hottest) {
+            let count = self.executions[address];
+            let instruction = memory[address];
+            let _ = writeln!(
+                report,
+                "{:>11}  {:>5.1}%  {:04X}     {:04X}  {}",
+                count,
+                count as f64 * 100.0 / total,
+                address,
+                instruction,
+                disassemble_instruction(instruction, address as u16)
+            );
+        }
+
+        let mut subroutines: Vec<(u16, SubroutineStats)> = self.subroutines().into_iter().collect();
+        subroutines.sort_by_key(|(_, stats)| Reverse(stats.total_instructions));
+
+        let _ = writeln!(report, "\nSubroutines:");
+        let _ = writeln!(report, "      calls         total           own  entry");
+        let _ = writeln!(
+            report,
+            "{:>11}  {:>12}  {:>12}  top level",
+            "-", instructions, self.top_level_instructions
+        );
+        for (entry, stats) in subroutines {
+            let line = format!(
+                "{:>11}  {:>12}  {:>12}  {:04X}  {}",
+                stats.calls,
+                stats.total_instructions,
+                stats.own_instructions,
+                entry,
+                symbols.name(entry).unwrap_or_default()
+            );
+            let _ = writeln!(report, "{}", line.trim_end());
+        }
+
+        let _ = writeln!(report, "\nMemory accesses:");
+        let _ = writeln!(
+            report,
+            "  {:<34}  {:>11}  {:>11}",
+            "region", "loads", "stores"
+        );
+        for ((name, addresses), stats) in REGIONS.iter().zip(&self.regions) {
+            let _ = writeln!(
+                report,
+                "  {:<34}  {:>11}  {:>11}",
+                format!("{} {:04X}-{:04X}", name, addresses.start(), addresses.end()),
+                stats.loads,
+                stats.stores
+            );
+        }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::MemoryConsole;
+    use crate::State;
+
+    /// Profiles steps of a program, each executing an instruction at an
+    /// address and jumping to the next one.
+    fn profile(steps: &[(u16, u16, u16)]) -> Profiler {
+        let mut state = State::with_console(Box::new(MemoryConsole::new(&[])));
+        let mut call_stack = CallStack::new();
+        let mut profiler = Profiler::new();
+
+        for &(pc, instruction, next_pc) in steps {
+            state.pc = next_pc;
+            let event = call_stack.follow(pc, instruction, &StepOutcome::Executed, &state);
+            profiler.record(pc, &StepOutcome::Executed, &call_stack, event, &[]);
+        }
+
+        profiler
+    }
+
+    #[test]
+    fn counts_subroutines_and_trap_routines_between_their_entry_and_return() {
+        let profiler = profile(&[
+            (0x3000, 0x4802, 0x3003), // JSR SQUARE
+            (0x3003, 0xF022, 0x0400), // SQUARE: PUTS
+            (0x0400, 0x1020, 0x0401), // ADD R0, R0, #0
+            (0x0401, 0x8000, 0x3004), // RTI
+            (0x3004, 0xC1C0, 0x3001), // RET
+        ]);
+
+        assert_eq!(profiler.instructions(), 5);
+        assert_eq!(profiler.top_level_instructions(), 1);
+        assert_eq!(profiler.executions(0x3003), 1);
+        assert_eq!(
+            profiler.subroutines().into_iter().collect::<Vec<_>>(),
+            [
+                (
+                    0x0400,
+                    SubroutineStats {
+                        calls: 1,
+                        own_instructions: 2,
+                        total_instructions: 2,
+                    }
+                ),
+                (
+                    0x3003,
+                    SubroutineStats {
+                        calls: 1,
+                        own_instructions: 2,
+                        total_instructions: 4,
+                    }
+                ),
+            ]
+        );
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert(0x3000, "START");
+        symbols.insert(0x3003, "SQUARE");
+        let mut folded = Vec::new();
+        profiler.write_folded(&mut folded, &symbols).unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "START 1\nSTART;SQUARE 2\nSTART;SQUARE;0x0400 2\n"
+        );
+    }
+
+    #[test]
+    fn subroutines_still_running_count_up_to_now() {
+        let profiler = profile(&[
+            (0x3000, 0x4802, 0x3003), // JSR LOOP
+            (0x3003, 0x0FFF, 0x3003), // LOOP: BRnzp LOOP
+            (0x3003, 0x0FFF, 0x3003),
+        ]);
+
+        let stats = profiler.subroutines()[&0x3003];
+        assert_eq!(stats.calls, 1);
+        assert_eq!(stats.total_instructions, 2);
+        assert_eq!(profiler.frames.len(), 1);
+    }
+
+    #[test]
+    fn counts_loads_and_stores_by_region() {
+        let mut profiler = Profiler::new();
+        let accesses = [
+            (Access::Read, 0x3005),
+            (Access::Write, 0x4000),
+            (Access::Read, 0xFE02),
+        ];
+        profiler.record(
+            0x3000,
+            &StepOutcome::Executed,
+            &CallStack::new(),
+            None,
+            &accesses,
+        );
+
+        assert_eq!(
+            profiler.regions()[3],
+            RegionStats {
+                loads: 1,
+                stores: 1
+            }
+        );
+        assert_eq!(
+            profiler.regions()[4],
+            RegionStats {
+                loads: 1,
+                stores: 0
+            }
+        );
+        assert_eq!(profiler.regions()[0], RegionStats::default());
+    }
+}
@@ -1,39 +1,143 @@
+use super::console::{Console, TerminalConsole};
+use super::device::{DeviceBus, DeviceContext, Interrupt, DEVICE_REGISTERS};
+use super::display::Display;
+use super::error::{Fault, Snapshot};
+use super::history::MemoryWrite;
+use super::keyboard::Keyboard;
 use super::opcode::Trapcode;
-use std::io::{Read, Write};
+use super::timer::Timer;
+use super::vm::StepOutcome;
+use super::watchpoint::{Access, WatchHit, Watchpoint};
 
-const KEYBOARD_STATUS_REGISTER: u16 = 0xFE00;
-const KEYBOARD_DATA_REGISTER: u16 = 0xFE02;
+/// User programs may only access memory from here up to the device registers
+const USER_SPACE_START: u16 = 0x3000;
+
+/// Number of words in the address space
+pub const MEMORY_SIZE: usize = 1 << 16;
+
+/// The machine control register, which no device may claim. Clearing its
+/// [`CLOCK_ENABLE`] bit stops the machine
+pub const MACHINE_CONTROL_REGISTER: u16 = 0xFFFE;
+
+/// Bit of the machine control register that keeps the clock running
+pub const CLOCK_ENABLE: u16 = 1 << 15;
+
+/// Base address of the interrupt vector table, holding the addresses of the
+/// interrupt and exception service routines
+const INTERRUPT_VECTOR_TABLE: u16 = 0x0100;
 
 const F_POS: u16 = 1;
 const F_ZERO: u16 = 1 << 1;
 const F_NEG: u16 = 1 << 2;
 
+/// Mask of the condition codes in the processor status register
+const PSR_CONDITION_CODES: u16 = 0x7;
+/// Mask of the priority level in the processor status register
+const PSR_PRIORITY: u16 = 0x7 << 8;
+/// Bit of the processor status register set while running in user mode
+const PSR_USER_MODE: u16 = 1 << 15;
+
+/// Where the supervisor stack starts, growing down towards system space
+const SUPERVISOR_STACK_START: u16 = 0x3000;
+/// Where the user stack starts, right below the device registers
+const USER_STACK_START: u16 = 0xFE00;
+
+/// Register used as the stack pointer, both by programs and when entering or
+/// returning from a service routine
+pub(crate) const STACK_POINTER: usize = 6;
+
+/// Privilege mode the processor is running in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Privilege {
+    /// System mode, with access to the whole address space
+    Supervisor,
+    /// Unprivileged mode programs usually run in
+    User,
+}
+
 #[derive(Debug)]
 pub struct State {
     /// Array of registers R0 through R7
     pub registers: [u16; 8],
     /// Program counter
     pub pc: u16,
-    /// Condition flags, only the first three bits are relevant
-    pub cflags: u16,
-    /// Array holding the entire memory
-    pub memory: [u16; u16::MAX as usize],
-    /// Whether the vm is running or not
-    pub running: bool,
+    /// Processor status register: privilege mode in bit 15, priority level in
+    /// bits 10 to 8 and the condition flags in bits 2 to 0
+    pub psr: u16,
+    /// User stack pointer, saved here while running in supervisor mode
+    pub saved_usp: u16,
+    /// Supervisor stack pointer, saved here while running in user mode
+    pub saved_ssp: u16,
+    /// Array holding the entire memory, including the machine control
+    /// register whose clock enable bit tells whether the vm is running
+    pub memory: [u16; MEMORY_SIZE],
+    /// Whether the console ran out of input, which stops the vm
+    pub input_exhausted: bool,
+    /// Where the trap routines and the devices read and write bytes
+    pub console: Box<dyn Console>,
+    /// Memory-mapped devices, starting with the keyboard, the display and the
+    /// timer
+    pub devices: DeviceBus,
+    /// Memory the program is watched accessing
+    pub watchpoints: Vec<Watchpoint>,
+    /// Accesses that matched a watchpoint, to be drained by whoever set them
+    pub watch_hits: Vec<WatchHit>,
+    /// When set, every word written to memory is logged here, which is how
+    /// steps are recorded for reverse execution
+    pub write_log: Option<Vec<MemoryWrite>>,
+    /// When set, every access the program makes to memory, apart from
+    /// instruction fetches, is logged here, which is how the profiler counts
+    /// loads and stores
+    pub access_log: Option<Vec<(Access, u16)>>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl State {
     pub fn new() -> State {
+        State::with_console(Box::new(TerminalConsole::new()))
+    }
+
+    /// Creates a machine talking to `console`. It starts in supervisor mode
+    /// at priority 0, with R6 holding the supervisor stack pointer (0x3000)
+    /// and the user one (0xFE00) saved, so programs may use the device
+    /// registers directly. Call [`State::set_privilege`] to run them in user
+    /// mode instead.
+    pub fn with_console(console: Box<dyn Console>) -> State {
+        let mut registers = [0; 8];
+        registers[STACK_POINTER] = SUPERVISOR_STACK_START;
+
+        // The clock starts enabled and runs until something clears it.
+        let mut memory = [0; MEMORY_SIZE];
+        memory[MACHINE_CONTROL_REGISTER as usize] = CLOCK_ENABLE;
+
+        let mut devices = DeviceBus::new();
+        devices.attach(Box::new(Keyboard::new()));
+        devices.attach(Box::new(Display::new()));
+        devices.attach(Box::new(Timer::new()));
+
         State {
-            registers: [0; 8],
+            registers,
             pc: 0x300,
-            cflags: 0,
-            memory: [0; u16::MAX as usize],
-            running: true,
+            psr: 0,
+            saved_usp: USER_STACK_START,
+            saved_ssp: SUPERVISOR_STACK_START,
+            memory,
+            input_exhausted: false,
+            console,
+            devices,
+            watchpoints: Vec::new(),
+            watch_hits: Vec::new(),
+            write_log: None,
+            access_log: None,
         }
     }
 
-    pub fn add(&mut self, instruction: u16) {
+    pub fn add(&mut self, instruction: u16) -> Result<(), Fault> {
         let dr = get_dr(instruction);
         let sr1 = (instruction >> 6) & 0x07;
 
@@ -47,9 +151,11 @@ impl State {
         }
 
         self.update_flags(dr);
+
+        Ok(())
     }
 
-    pub fn and(&mut self, instruction: u16) {
+    pub fn and(&mut self, instruction: u16) -> Result<(), Fault> {
         let dr = get_dr(instruction);
         let sr1 = (instruction >> 6) & 0x07;
 
@@ -63,24 +169,30 @@ impl State {
         }
 
         self.update_flags(dr);
+
+        Ok(())
     }
 
-    pub fn conditional_branch(&mut self, instruction: u16) {
+    pub fn conditional_branch(&mut self, instruction: u16) -> Result<(), Fault> {
         let condition_flag = (instruction >> 9) & 0x07;
 
-        if (condition_flag & self.cflags) != 0 {
+        if (condition_flag & self.psr) != 0 {
             let offset = sign_extend(instruction & 0x01FF, 9);
             self.pc += offset;
         }
+
+        Ok(())
     }
 
-    pub fn jump(&mut self, instruction: u16) {
+    pub fn jump(&mut self, instruction: u16) -> Result<(), Fault> {
         let base_register = (instruction >> 6) & 0x07;
 
         self.pc = self.registers[base_register as usize];
+
+        Ok(())
     }
 
-    pub fn jump_to_subroutine(&mut self, instruction: u16) {
+    pub fn jump_to_subroutine(&mut self, instruction: u16) -> Result<(), Fault> {
         self.registers[7] = self.pc;
 
         if ((instruction >> 11) & 0x1) == 0 {
@@ -90,67 +202,83 @@ impl State {
             let offset = sign_extend(instruction & 0x07FF, 11);
             self.pc += offset;
         }
+
+        Ok(())
     }
 
-    pub fn load(&mut self, instruction: u16) {
+    pub fn load(&mut self, instruction: u16) -> Result<(), Fault> {
         let dr = get_dr(instruction);
         let offset = sign_extend(instruction & 0x01FF, 9);
 
-        self.registers[dr as usize] = self.mem_read(self.pc + offset);
+        self.registers[dr as usize] = self.mem_read(self.pc + offset)?;
         self.update_flags(dr);
+
+        Ok(())
     }
 
-    pub fn load_indirect(&mut self, instruction: u16) {
+    pub fn load_indirect(&mut self, instruction: u16) -> Result<(), Fault> {
         let dr = get_dr(instruction);
         let offset = sign_extend(instruction & 0x01FF, 9);
-        let address = self.mem_read(self.pc + offset);
+        let address = self.mem_read(self.pc + offset)?;
 
-        self.registers[dr as usize] = self.mem_read(address);
+        self.registers[dr as usize] = self.mem_read(address)?;
         self.update_flags(dr);
+
+        Ok(())
     }
 
-    pub fn load_base_plus_offset(&mut self, instruction: u16) {
+    pub fn load_base_plus_offset(&mut self, instruction: u16) -> Result<(), Fault> {
         let dr = get_dr(instruction);
         let base_register = (instruction >> 6) & 0x07;
         let offset = sign_extend(instruction & 0x3F, 6);
 
         self.registers[dr as usize] =
-            self.mem_read(self.registers[base_register as usize] + offset);
+            self.mem_read(self.registers[base_register as usize] + offset)?;
         self.update_flags(dr);
+
+        Ok(())
     }
 
-    pub fn load_effective_address(&mut self, instruction: u16) {
+    pub fn load_effective_address(&mut self, instruction: u16) -> Result<(), Fault> {
         let dr = get_dr(instruction);
         let offset = sign_extend(instruction & 0x01FF, 9);
 
         self.registers[dr as usize] = self.pc + offset;
         self.update_flags(dr);
+
+        Ok(())
     }
 
-    pub fn not(&mut self, instruction: u16) {
+    pub fn not(&mut self, instruction: u16) -> Result<(), Fault> {
         let dr = get_dr(instruction);
         let sr = (instruction >> 6) & 0x07;
 
         self.registers[dr as usize] = !self.registers[sr as usize];
         self.update_flags(dr);
+
+        Ok(())
     }
 
-    pub fn store(&mut self, instruction: u16) {
+    pub fn store(&mut self, instruction: u16) -> Result<(), Fault> {
         let sr = (instruction >> 9) & 0x07;
         let offset = sign_extend(instruction & 0x01FF, 9);
 
-        self.mem_set(self.pc + offset, self.registers[sr as usize]);
+        self.mem_set(self.pc + offset, self.registers[sr as usize])?;
+
+        Ok(())
     }
 
-    pub fn store_indirect(&mut self, instruction: u16) {
+    pub fn store_indirect(&mut self, instruction: u16) -> Result<(), Fault> {
         let sr = (instruction >> 9) & 0x07;
         let offset = sign_extend(instruction & 0x01FF, 9);
-        let address = self.mem_read(self.pc + offset);
+        let address = self.mem_read(self.pc + offset)?;
 
-        self.mem_set(address, self.registers[sr as usize]);
+        self.mem_set(address, self.registers[sr as usize])?;
+
+        Ok(())
     }
 
-    pub fn store_base_plus_offset(&mut self, instruction: u16) {
+    pub fn store_base_plus_offset(&mut self, instruction: u16) -> Result<(), Fault> {
         let sr = (instruction >> 9) & 0x07;
         let base_register = (instruction >> 6) & 0x07;
         let offset = sign_extend(instruction & 0x3F, 6);
@@ -158,131 +286,409 @@ impl State {
         self.mem_set(
             self.registers[base_register as usize] + offset,
             self.registers[sr as usize],
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns from an interrupt or exception service routine by popping the
+    /// PC and PSR off the supervisor stack. Only allowed in supervisor mode.
+    pub fn return_from_interrupt(&mut self, instruction: u16) -> Result<(), Fault> {
+        if self.privilege() == Privilege::User {
+            return Err(Fault::PrivilegeViolation(instruction));
+        }
+
+        self.pc = self.pop()?;
+        let psr = self.pop()?;
+
+        self.set_privilege(if psr & PSR_USER_MODE == 0 {
+            Privilege::Supervisor
+        } else {
+            Privilege::User
+        });
+        self.psr = psr;
+
+        Ok(())
+    }
+
+    /// Ticks the devices and returns the interrupt they requested, if it has
+    /// a higher priority than the one the processor is running at.
+    pub fn pending_interrupt(&mut self) -> Result<Option<Interrupt>, Fault> {
+        let mut context = DeviceContext::new(
+            &mut *self.console,
+            &mut self.memory[MACHINE_CONTROL_REGISTER as usize],
+            &mut self.input_exhausted,
         );
+        let interrupt = self.devices.tick(&mut context)?;
+
+        Ok(interrupt.filter(|interrupt| interrupt.priority > self.priority()))
+    }
+
+    /// Enters the service routine for the interrupt `vector`: the PSR and PC
+    /// are pushed onto the supervisor stack, the processor switches to
+    /// supervisor mode at the given priority and jumps to the address held in
+    /// the interrupt vector table.
+    pub fn interrupt(&mut self, vector: u8, priority: u16) -> Result<(), Fault> {
+        let psr = self.psr;
+
+        self.set_privilege(Privilege::Supervisor);
+        self.push(psr)?;
+        self.push(self.pc)?;
+        self.set_priority(priority);
+
+        self.pc = self.system_read(INTERRUPT_VECTOR_TABLE + vector as u16)?;
+
+        Ok(())
+    }
+
+    /// Raises the exception `vector`, entering its service routine like an
+    /// interrupt but without changing the priority level.
+    pub fn exception(&mut self, vector: u8) -> Result<(), Fault> {
+        self.interrupt(vector, self.priority())
+    }
+
+    /// The privilege mode, taken from the PSR.
+    pub fn privilege(&self) -> Privilege {
+        if self.psr & PSR_USER_MODE == 0 {
+            Privilege::Supervisor
+        } else {
+            Privilege::User
+        }
+    }
+
+    /// Switches privilege mode, saving the current stack pointer in R6 and
+    /// loading the other mode's one.
+    pub fn set_privilege(&mut self, privilege: Privilege) {
+        match (self.privilege(), privilege) {
+            (Privilege::Supervisor, Privilege::User) => {
+                self.saved_ssp = self.registers[STACK_POINTER];
+                self.registers[STACK_POINTER] = self.saved_usp;
+                self.psr |= PSR_USER_MODE;
+            }
+            (Privilege::User, Privilege::Supervisor) => {
+                self.saved_usp = self.registers[STACK_POINTER];
+                self.registers[STACK_POINTER] = self.saved_ssp;
+                self.psr &= !PSR_USER_MODE;
+            }
+            _ => {}
+        }
+    }
+
+    /// The priority level the processor is running at, from 0 to 7.
+    pub fn priority(&self) -> u16 {
+        (self.psr & PSR_PRIORITY) >> 8
+    }
+
+    /// Sets the priority level, only the lowest three bits are used.
+    pub fn set_priority(&mut self, priority: u16) {
+        self.psr = (self.psr & !PSR_PRIORITY) | ((priority & 0x7) << 8);
+    }
+
+    /// The condition flags, i.e. the lowest three bits of the PSR.
+    pub fn condition_codes(&self) -> u16 {
+        self.psr & PSR_CONDITION_CODES
+    }
+
+    fn push(&mut self, value: u16) -> Result<(), Fault> {
+        self.registers[STACK_POINTER] -= 1;
+        self.mem_set(self.registers[STACK_POINTER], value)
+    }
+
+    fn pop(&mut self) -> Result<u16, Fault> {
+        let value = self.mem_read(self.registers[STACK_POINTER])?;
+        self.registers[STACK_POINTER] += 1;
+
+        Ok(value)
     }
 
-    pub fn trap(&mut self, instruction: u16) {
-        let trap_code = instruction & 0xFF;
-
-        match trap_code.try_into() {
-            Ok(Trapcode::GETC) => self.getc(),
-            Ok(Trapcode::OUT) => self.out(),
-            Ok(Trapcode::PUTS) => self.puts(),
-            Ok(Trapcode::IN) => self.input(),
-            Ok(Trapcode::PUTSP) => self.putsp(),
-            Ok(Trapcode::HALT) => self.halt(),
-            _ => panic!(
-                "Unexpected trap code. Code: {}\nRegisters: {:?}\nPc: 0x{:x}\nZF: {}\nNF: {}\nPF: {}\n",
-                trap_code, self.registers, self.pc, (self.cflags >> 1) & 0x1, (self.cflags >>2) & 0x1, self.cflags & 0x1
-            ),
+    pub fn trap(&mut self, instruction: u16) -> Result<StepOutcome, Fault> {
+        let trap_code = (instruction & 0xFF) as u8;
+
+        // GETC and IN would block until a key is pressed, so instead we rewind
+        // the PC and let the caller decide when to retry the TRAP.
+        let reads_input = matches!(
+            (trap_code as u16).try_into(),
+            Ok(Trapcode::GETC) | Ok(Trapcode::IN)
+        );
+        if reads_input && !self.console.poll_key()? {
+            self.pc -= 1;
+            return Ok(StepOutcome::WaitingForInput);
         }
+
+        match (trap_code as u16).try_into() {
+            Ok(Trapcode::GETC) => self.getc()?,
+            Ok(Trapcode::OUT) => self.out()?,
+            Ok(Trapcode::PUTS) => self.puts()?,
+            Ok(Trapcode::IN) => self.input()?,
+            Ok(Trapcode::PUTSP) => self.putsp()?,
+            Ok(Trapcode::HALT) => {
+                self.halt()?;
+                return Ok(StepOutcome::Halted);
+            }
+            Err(_) => return Err(Fault::UnknownTrap(trap_code)),
+        }
+
+        Ok(StepOutcome::Trapped(trap_code))
+    }
+
+    /// Executes a TRAP the way the ISA specifies: R7 gets the return address
+    /// and the PC jumps to the routine listed in the trap vector table.
+    pub fn trap_through_table(&mut self, instruction: u16) -> Result<StepOutcome, Fault> {
+        let trap_vector = instruction & 0xFF;
+
+        self.registers[7] = self.pc;
+        self.pc = self.system_read(trap_vector)?;
+
+        Ok(StepOutcome::Trapped(trap_vector as u8))
     }
 
-    pub fn illegal_opcode(&self) {
-        panic!("Illegal opcode encountered")
+    /// Executes a TRAP through the trap vector table, entering the routine in
+    /// supervisor mode: like an interrupt, the PSR and PC are pushed onto the
+    /// supervisor stack, so the routine returns to the caller's mode with RTI.
+    pub fn trap_to_supervisor(&mut self, instruction: u16) -> Result<StepOutcome, Fault> {
+        let psr = self.psr;
+
+        self.set_privilege(Privilege::Supervisor);
+        self.push(psr)?;
+        self.push(self.pc)?;
+
+        self.trap_through_table(instruction)
     }
 
-    fn getc(&mut self) {
-        let input = get_char();
-        self.registers[0] = input as u16;
+    /// The registers, program counter and processor status register as they
+    /// are now.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers,
+            pc: self.pc,
+            psr: self.psr,
+        }
+    }
+
+    fn getc(&mut self) -> Result<(), Fault> {
+        if let Some(input) = self.read_input()? {
+            self.registers[0] = input as u16;
+        }
+
+        Ok(())
     }
 
-    fn out(&self) {
+    fn out(&mut self) -> Result<(), Fault> {
         let value = self.registers[0] as u8;
 
-        print!("{}", value as char);
-        std::io::stdout().flush().unwrap();
+        self.console.write_byte(value)?;
+        self.console.flush()?;
+
+        Ok(())
     }
 
-    fn puts(&mut self) {
+    fn puts(&mut self) -> Result<(), Fault> {
         let mut index = self.registers[0];
 
         loop {
-            let next_char = self.mem_read(index) as u8;
+            let next_char = self.system_read(index)? as u8;
             if next_char == 0 {
                 break;
             }
 
-            print!("{}", next_char as char);
+            self.console.write_byte(next_char)?;
             index += 1;
         }
-        std::io::stdout().flush().unwrap();
+        self.console.flush()?;
+
+        Ok(())
     }
 
-    fn input(&mut self) {
-        print!("Enter a character: ");
-        std::io::stdout().flush().unwrap();
-        let input = get_char();
+    fn input(&mut self) -> Result<(), Fault> {
+        self.write_str("Enter a character: ")?;
+        self.console.flush()?;
+        if let Some(input) = self.read_input()? {
+            self.registers[0] = input as u16;
+            self.console.write_byte(input)?;
+        }
 
-        self.registers[0] = input as u16;
-        print!("{}", input as char);
+        Ok(())
     }
 
-    fn putsp(&mut self) {
+    fn putsp(&mut self) -> Result<(), Fault> {
         let mut index = self.registers[0];
 
         loop {
-            let next_word = self.mem_read(index);
+            let next_word = self.system_read(index)?;
             let low = (next_word & 0xFF) as u8;
             let high = (next_word >> 8) as u8;
 
             if low == 0 {
                 break;
             }
-            print!("{}", low as char);
+            self.console.write_byte(low)?;
 
             if high == 0 {
                 break;
             }
-            print!("{}", high as char);
+            self.console.write_byte(high)?;
 
             index += 1;
         }
-        std::io::stdout().flush().unwrap();
-    }
-
-    fn halt(&mut self) {
-        std::io::stdout().flush().unwrap();
-        self.running = false;
-    }
-
-    pub fn mem_read(&mut self, address: u16) -> u16 {
-        // The way the keyboard status and data registers would be used normally
-        // is: whenever the user presses a key, the keyboard
-        // sets the status register's highest bit to one and the value of the
-        // key pressed into the data register; but that's not what MY keyboard
-        // will actually do when I press a button, so I have to emulate it.
-
-        // The way we do it is the following: when the executing program wants to read
-        // the status register, we check if a key has been pressed in the past; if it has, we set
-        // the status register's highest bit to one and its value to the data register.
-        // Otherwise we just set the status register to zero.
-        if address == KEYBOARD_STATUS_REGISTER {
-            if check_key() {
-                self.memory[KEYBOARD_STATUS_REGISTER as usize] = 1 << 15;
-                self.memory[KEYBOARD_DATA_REGISTER as usize] = get_char() as u16;
-            } else {
-                self.memory[KEYBOARD_STATUS_REGISTER as usize] = 0;
+        self.console.flush()?;
+
+        Ok(())
+    }
+
+    fn halt(&mut self) -> Result<(), Fault> {
+        self.console.flush()?;
+        self.stop();
+
+        Ok(())
+    }
+
+    /// Whether the clock enable bit of the machine control register is set.
+    pub fn running(&self) -> bool {
+        self.memory[MACHINE_CONTROL_REGISTER as usize] & CLOCK_ENABLE != 0
+    }
+
+    /// Stops the machine by clearing the clock enable bit of the machine
+    /// control register.
+    pub fn stop(&mut self) {
+        self.memory[MACHINE_CONTROL_REGISTER as usize] &= !CLOCK_ENABLE;
+    }
+
+    /// Reads a byte from the console. Once the input is exhausted there is
+    /// nothing a program waiting for a key can do, so the vm is stopped.
+    fn read_input(&mut self) -> Result<Option<u8>, Fault> {
+        let input = self.console.read_byte()?;
+
+        if input.is_none() {
+            self.input_exhausted = true;
+            self.stop();
+        }
+
+        Ok(input)
+    }
+
+    fn write_str(&mut self, text: &str) -> Result<(), Fault> {
+        for byte in text.bytes() {
+            self.console.write_byte(byte)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads memory on behalf of the running program. In user mode, touching
+    /// system space or the device registers is an access control violation.
+    pub fn mem_read(&mut self, address: u16) -> Result<u16, Fault> {
+        self.check_access(address)?;
+        let value = self.system_read(address)?;
+        self.watch(Access::Read, address, value, value);
+        if let Some(log) = &mut self.access_log {
+            log.push((Access::Read, address));
+        }
+
+        Ok(value)
+    }
+
+    /// Writes memory on behalf of the running program, see [`State::mem_read`].
+    pub fn mem_set(&mut self, address: u16, value: u16) -> Result<(), Fault> {
+        self.check_access(address)?;
+        let old = self.mem_get(address);
+        self.system_write(address, value)?;
+        self.watch(Access::Write, address, old, value);
+        if let Some(log) = &mut self.access_log {
+            log.push((Access::Write, address));
+        }
+
+        Ok(())
+    }
+
+    /// The value at `address`, read without the side effects reading a device
+    /// register may have and without checking access rights.
+    pub fn mem_get(&self, address: u16) -> u16 {
+        self.devices
+            .peek(address)
+            .unwrap_or(self.memory[address as usize])
+    }
+
+    /// Fetches the instruction at the PC. Like [`State::mem_read`], but
+    /// invisible to watchpoints.
+    pub fn fetch(&mut self) -> Result<u16, Fault> {
+        self.check_access(self.pc)?;
+        self.system_read(self.pc)
+    }
+
+    fn watch(&mut self, access: Access, address: u16, old: u16, value: u16) {
+        for (index, watchpoint) in self.watchpoints.iter().enumerate() {
+            if watchpoint.matches(access, address, old, value) {
+                self.watch_hits.push(WatchHit {
+                    watchpoint: index,
+                    access,
+                    address,
+                    old,
+                    value,
+                });
             }
         }
+    }
+
+    fn check_access(&self, address: u16) -> Result<(), Fault> {
+        let user_space = (USER_SPACE_START..*DEVICE_REGISTERS.start()).contains(&address);
+
+        if self.privilege() == Privilege::User && !user_space {
+            Err(Fault::AccessViolation(address))
+        } else {
+            Ok(())
+        }
+    }
 
-        return self.memory[address as usize];
+    /// Reads memory with supervisor rights, the way the host side trap
+    /// routines and the interrupt machinery do.
+    pub fn system_read(&mut self, address: u16) -> Result<u16, Fault> {
+        if let Some(device) = self.devices.find(address) {
+            let mut context = DeviceContext::new(
+                &mut *self.console,
+                &mut self.memory[MACHINE_CONTROL_REGISTER as usize],
+                &mut self.input_exhausted,
+            );
+            return device.read(address, &mut context);
+        }
+
+        Ok(self.memory[address as usize])
     }
 
-    fn mem_set(&mut self, address: u16, value: u16) {
+    /// Writes memory with supervisor rights, see [`State::system_read`].
+    pub fn system_write(&mut self, address: u16, value: u16) -> Result<(), Fault> {
+        if let Some(device) = self.devices.find(address) {
+            let mut context = DeviceContext::new(
+                &mut *self.console,
+                &mut self.memory[MACHINE_CONTROL_REGISTER as usize],
+                &mut self.input_exhausted,
+            );
+            return device.write(address, value, &mut context);
+        }
+
+        if let Some(log) = &mut self.write_log {
+            log.push(MemoryWrite {
+                address,
+                old: self.memory[address as usize],
+                value,
+            });
+        }
         self.memory[address as usize] = value;
+
+        Ok(())
     }
 
     fn update_flags(&mut self, register: u16) {
         let value = self.registers[register as usize];
 
-        if value == 0 {
-            self.cflags = F_ZERO;
+        let flag = if value == 0 {
+            F_ZERO
         } else if (value >> 15) == 1 {
-            self.cflags = F_NEG;
+            F_NEG
         } else {
-            self.cflags = F_POS;
-        }
+            F_POS
+        };
+
+        self.psr = (self.psr & !PSR_CONDITION_CODES) | flag;
     }
 }
 
@@ -294,27 +700,77 @@ fn sign_extend(value: u16, bit_count: u16) -> u16 {
     }
 }
 
-fn check_key() -> bool {
-    let mut readfds = nix::sys::select::FdSet::new();
-    readfds.insert(0);
-    let mut timeout: nix::sys::time::TimeVal = nix::sys::time::TimeValLike::seconds(0);
-
-    return nix::sys::select::select(1, &mut readfds, None, None, &mut timeout).unwrap() != 0;
-}
-
 fn get_dr(instruction: u16) -> u16 {
-    return (instruction >> 9) & 0x7;
+    (instruction >> 9) & 0x7
 }
 
-fn get_char() -> u8 {
-    let input = std::io::stdin()
-        .bytes()
-        .next()
-        .and_then(|result| result.ok())
-        .map(|byte| byte as u8);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::MemoryConsole;
+
+    fn state() -> State {
+        State::with_console(Box::new(MemoryConsole::new(&[])))
+    }
+
+    #[test]
+    fn starts_in_supervisor_mode_with_the_user_stack_saved() {
+        let mut state = state();
+
+        assert_eq!(state.privilege(), Privilege::Supervisor);
+        assert_eq!(state.priority(), 0);
+        assert_eq!(state.registers[STACK_POINTER], SUPERVISOR_STACK_START);
+
+        state.set_privilege(Privilege::User);
+        assert_eq!(state.registers[STACK_POINTER], USER_STACK_START);
+        assert_eq!(state.saved_ssp, SUPERVISOR_STACK_START);
+    }
+
+    #[test]
+    fn trap_through_table_links_r7_and_returns_with_ret() {
+        let mut state = state();
+        let stack = state.registers[STACK_POINTER];
+        state.memory[0x40] = 0x4000;
+        state.pc = 0x3001;
+
+        let outcome = state.trap_through_table(0xF040).unwrap();
+
+        assert!(matches!(outcome, StepOutcome::Trapped(0x40)));
+        assert_eq!(state.pc, 0x4000);
+        assert_eq!(state.registers[7], 0x3001);
+        assert_eq!(state.registers[STACK_POINTER], stack);
+        assert_eq!(state.privilege(), Privilege::Supervisor);
+
+        state.jump(0xC1C0).unwrap();
+        assert_eq!(state.pc, 0x3001);
+    }
 
-    match input {
-        Some(value) => value,
-        _ => panic!("Error reading from stdin"),
+    #[test]
+    fn trap_to_supervisor_enters_supervisor_mode_until_rti() {
+        let mut state = state();
+        state.set_privilege(Privilege::User);
+        let user_psr = state.psr;
+        let user_stack = state.registers[STACK_POINTER];
+        state.memory[0x22] = 0x0400;
+        state.pc = 0x3001;
+
+        let outcome = state.trap_to_supervisor(0xF022).unwrap();
+
+        assert!(matches!(outcome, StepOutcome::Trapped(0x22)));
+        assert_eq!(state.privilege(), Privilege::Supervisor);
+        assert_eq!(state.pc, 0x0400);
+        assert_eq!(state.registers[7], 0x3001);
+        let stack = state.registers[STACK_POINTER];
+        assert_eq!(stack, SUPERVISOR_STACK_START - 2);
+        assert_eq!(state.memory[stack as usize], 0x3001);
+        assert_eq!(state.memory[stack as usize + 1], user_psr);
+
+        state.return_from_interrupt(0x8000).unwrap();
+
+        assert_eq!(state.privilege(), Privilege::User);
+        assert_eq!(state.psr, user_psr);
+        assert_eq!(state.pc, 0x3001);
+        assert_eq!(state.registers[STACK_POINTER], user_stack);
+        assert_eq!(state.saved_ssp, SUPERVISOR_STACK_START);
     }
 }
@@ -0,0 +1,90 @@
+//! Names for addresses, read from the symbol tables assemblers write.
+//!
+//! The `.sym` files lc3as writes next to the object files look like this:
+//!
+//! ```text
+//! // Symbol table
+//! // Scope level 0:
+//! //    Symbol Name       Page Address
+//! //    ----------------  ------------
+//! //    START             3000
+//! //    LOOP              3003
+//! ```
+//!
+//! Lines of a label and an address in hex are read, whether or not they are
+//! commented out and whether or not the address starts with an `x`. Anything
+//! else is skipped.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    /// Keyed by address
+    names: BTreeMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    /// Reads the symbols of a `.sym` file.
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<SymbolTable> {
+        let mut symbols = SymbolTable::new();
+        symbols.add(&std::fs::read_to_string(path)?);
+
+        Ok(symbols)
+    }
+
+    /// Adds the symbols of the contents of a `.sym` file. Symbols for an
+    /// address that already has one replace it.
+    pub fn add(&mut self, table: &str) {
+        for line in table.lines() {
+            let line = line.trim_start().trim_start_matches("//");
+            let mut words = line.split_whitespace();
+
+            let (Some(name), Some(address), None) = (words.next(), words.next(), words.next())
+            else {
+                continue;
+            };
+            let address = address.trim_start_matches(['x', 'X']);
+            let is_label = name
+                .chars()
+                .all(|character| character.is_alphanumeric() || character == '_');
+            if let (true, Ok(address)) = (is_label, u16::from_str_radix(address, 16)) {
+                self.insert(address, name);
+            }
+        }
+    }
+
+    /// Adds all the symbols of `other`, replacing the ones for the same
+    /// addresses.
+    pub fn extend(&mut self, other: &SymbolTable) {
+        self.names.extend(
+            other
+                .names
+                .iter()
+                .map(|(address, name)| (*address, name.clone())),
+        );
+    }
+
+    pub fn insert(&mut self, address: u16, name: &str) {
+        self.names.insert(address, name.to_string());
+    }
+
+    /// The name of `address`, if it has one.
+    pub fn name(&self, address: u16) -> Option<&str> {
+        self.names.get(&address).map(String::as_str)
+    }
+
+    /// The name of `address`, or the address itself in hex if it has none.
+    pub fn describe(&self, address: u16) -> String {
+        self.name(address)
+            .map_or_else(|| format!("0x{:04X}", address), str::to_string)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
@@ -0,0 +1,145 @@
+use super::device::{Device, DeviceContext, Interrupt};
+use super::error::Fault;
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+pub const TIMER_STATUS_REGISTER: u16 = 0xFE08;
+pub const TIMER_INTERVAL_REGISTER: u16 = 0xFE0A;
+pub const TIMER_CONTROL_REGISTER: u16 = 0xFE0C;
+
+/// Bit of the timer status register set once the interval has elapsed, until
+/// the status register is read
+const TIMER_EXPIRED: u16 = 1 << 15;
+/// Bit of the timer control register that starts the timer
+const TIMER_ENABLE: u16 = 1 << 15;
+/// Bit of the timer control register that enables timer interrupts
+const TIMER_INTERRUPT_ENABLE: u16 = 1 << 14;
+/// Bit of the timer control register selecting an interval in milliseconds
+/// rather than in executed instructions
+const TIMER_WALL_CLOCK: u16 = 1;
+/// Entry of the interrupt vector table for the timer
+const TIMER_INTERRUPT_VECTOR: u8 = 0x81;
+/// Priority level timer interrupts are raised at
+const TIMER_PRIORITY: u16 = 5;
+
+/// A timer that expires periodically, after a number of executed instructions
+/// or milliseconds set in its interval register (TMIR).
+///
+/// It is programmed through its control register (TMCR): bit 15 starts it,
+/// bit 14 enables interrupts and bit 0 selects milliseconds instead of
+/// instructions. Bit 15 of its status register (TMSR) is set every time the
+/// interval elapses, and while it is set and interrupts are enabled, the timer
+/// keeps requesting an interrupt. Reading the status register clears it, so a
+/// service routine has to read it before returning.
+#[derive(Debug)]
+pub struct Timer {
+    status: u16,
+    interval: u16,
+    control: u16,
+    /// Instructions executed since the interval last elapsed
+    instructions: u16,
+    /// When the interval last elapsed
+    started: Instant,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            status: 0,
+            interval: 0,
+            control: 0,
+            instructions: 0,
+            started: Instant::now(),
+        }
+    }
+
+    fn restart(&mut self) {
+        self.instructions = 0;
+        self.started = Instant::now();
+    }
+
+    fn elapsed(&self) -> bool {
+        if self.control & TIMER_WALL_CLOCK != 0 {
+            self.started.elapsed() >= Duration::from_millis(self.interval as u64)
+        } else {
+            self.instructions >= self.interval
+        }
+    }
+}
+
+impl Device for Timer {
+    fn addresses(&self) -> RangeInclusive<u16> {
+        TIMER_STATUS_REGISTER..=TIMER_CONTROL_REGISTER + 1
+    }
+
+    fn read(&mut self, address: u16, _context: &mut DeviceContext) -> Result<u16, Fault> {
+        match address {
+            TIMER_STATUS_REGISTER => Ok(std::mem::take(&mut self.status)),
+            TIMER_INTERVAL_REGISTER => Ok(self.interval),
+            TIMER_CONTROL_REGISTER => Ok(self.control),
+            _ => Ok(0),
+        }
+    }
+
+    fn peek(&self, address: u16) -> u16 {
+        match address {
+            TIMER_STATUS_REGISTER => self.status,
+            TIMER_INTERVAL_REGISTER => self.interval,
+            TIMER_CONTROL_REGISTER => self.control,
+            _ => 0,
+        }
+    }
+
+    fn write(
+        &mut self,
+        address: u16,
+        value: u16,
+        _context: &mut DeviceContext,
+    ) -> Result<(), Fault> {
+        // Changing the interval or starting the timer begins a fresh one, the
+        // status register belongs to the timer.
+        match address {
+            TIMER_INTERVAL_REGISTER => {
+                self.interval = value;
+                self.restart();
+            }
+            TIMER_CONTROL_REGISTER => {
+                if self.control & TIMER_ENABLE == 0 {
+                    self.restart();
+                }
+                self.control = value & (TIMER_ENABLE | TIMER_INTERRUPT_ENABLE | TIMER_WALL_CLOCK);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn tick(&mut self, _context: &mut DeviceContext) -> Result<Option<Interrupt>, Fault> {
+        if self.control & TIMER_ENABLE == 0 || self.interval == 0 {
+            return Ok(None);
+        }
+
+        // Ticks happen right before every instruction, so they count them.
+        self.instructions = self.instructions.saturating_add(1);
+        if self.elapsed() {
+            self.status |= TIMER_EXPIRED;
+            self.restart();
+        }
+
+        if self.control & TIMER_INTERRUPT_ENABLE != 0 && self.status & TIMER_EXPIRED != 0 {
+            Ok(Some(Interrupt {
+                vector: TIMER_INTERRUPT_VECTOR,
+                priority: TIMER_PRIORITY,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+}
@@ -0,0 +1,573 @@
+//! Execution traces, one record per executed instruction.
+//!
+//! A [`Tracer`] set with [`Vm::set_tracer`](crate::Vm::set_tracer) gets a
+//! [`TraceRecord`] for every step: the PC, the instruction word, the
+//! registers it changed, the memory it wrote and the condition codes it left.
+//! Records are written either as text, one line each:
+//!
+//! ```text
+//! 3001  3004  ST R0 0x3006              [3006]=0000->0001  CC=P
+//! 3003  E003  LEA R0 0x3007             R0=FFFF->3007  CC=P
+//! ```
+//!
+//! or in a compact binary format for long runs. A binary trace starts with
+//! the bytes `LC3T` and a version byte, followed by the records. Every value
+//! is big-endian, like in object files:
+//!
+//! - the PC and the instruction, two words
+//! - a flags byte: the condition codes in bits 0 to 2, and bit 3 set when an
+//!   interrupt was taken instead of executing the instruction, in which case
+//!   a byte with the interrupt vector follows
+//! - a byte with bit `n` set for every register Rn that changed, followed by
+//!   the old and new value of each of them, lowest register first
+//! - the number of memory writes, a word, followed by the address, old value
+//!   and new value of each of them
+//!
+//! A [`TraceReader`] reads traces in either format back.
+
+use super::disassembler::disassemble_instruction;
+use super::history::{Delta, MemoryWrite};
+use super::state::State;
+use std::fmt;
+use std::io::{BufRead, Read, Write};
+
+/// Bytes a binary trace starts with.
+pub const BINARY_MAGIC: &[u8; 4] = b"LC3T";
+/// Version of the binary format, written after [`BINARY_MAGIC`].
+pub const BINARY_VERSION: u8 = 2;
+
+/// Bit of the flags byte set for interrupts in binary traces.
+const INTERRUPT_FLAG: u8 = 1 << 3;
+
+/// How a [`Tracer`] writes its records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One line of text per record
+    Text,
+    /// The compact binary format described in the [module docs](self)
+    Binary,
+}
+
+/// A register an instruction changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterChange {
+    /// 0 to 7 for R0 to R7
+    pub register: u8,
+    pub old: u16,
+    pub new: u16,
+}
+
+/// What a single step did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Address of the instruction, or the PC when an interrupt was taken
+    pub pc: u16,
+    /// Word at the PC before the step
+    pub instruction: u16,
+    /// Vector of the interrupt taken instead of executing the instruction
+    pub interrupt: Option<u8>,
+    /// Registers that changed, lowest first
+    pub registers: Vec<RegisterChange>,
+    /// Memory written, in order
+    pub writes: Vec<MemoryWrite>,
+    /// Condition codes after the step, i.e. the lowest three bits of the PSR
+    pub condition_codes: u16,
+}
+
+impl TraceRecord {
+    /// Builds the record of a step from the machine state before it (in
+    /// `delta`) and after it.
+    pub(crate) fn new(
+        delta: &Delta,
+        instruction: u16,
+        interrupt: Option<u8>,
+        state: &State,
+    ) -> TraceRecord {
+        let registers = (0..8)
+            .filter(|index| delta.registers[*index] != state.registers[*index])
+            .map(|index| RegisterChange {
+                register: index as u8,
+                old: delta.registers[index],
+                new: state.registers[index],
+            })
+            .collect();
+
+        TraceRecord {
+            pc: delta.pc,
+            instruction,
+            interrupt,
+            registers,
+            writes: delta.writes.clone(),
+            condition_codes: state.condition_codes(),
+        }
+    }
+
+    /// The record as a line of text, without the line break.
+    pub fn to_text(&self) -> String {
+        let text = match self.interrupt {
+            Some(vector) => format!("INTERRUPT 0x{:02X}", vector),
+            None => disassemble_instruction(self.instruction, self.pc),
+        };
+        let mut line = format!("{:04X}  {:04X}  {:<24}", self.pc, self.instruction, text);
+
+        for change in &self.registers {
+            line.push_str(&format!(
+                "  R{}={:04X}->{:04X}",
+                change.register, change.old, change.new
+            ));
+        }
+        for write in &self.writes {
+            line.push_str(&format!(
+                "  [{:04X}]={:04X}->{:04X}",
+                write.address, write.old, write.value
+            ));
+        }
+        line.push_str(&format!("  CC={}", condition_codes(self.condition_codes)));
+
+        line
+    }
+
+    /// Parses a line written by [`TraceRecord::to_text`]. The disassembly is
+    /// skipped, it only depends on the instruction.
+    pub fn from_text(line: &str) -> Result<TraceRecord, String> {
+        let invalid = || format!("Invalid trace line: {}", line);
+        let hex = |word: &str| u16::from_str_radix(word, 16).map_err(|_| invalid());
+        let change = |change: &str| -> Result<(u16, u16), String> {
+            let (old, new) = change.split_once("->").ok_or_else(invalid)?;
+            Ok((hex(old)?, hex(new)?))
+        };
+
+        let mut words = line.split_whitespace();
+        let mut record = TraceRecord {
+            pc: hex(words.next().ok_or_else(invalid)?)?,
+            instruction: hex(words.next().ok_or_else(invalid)?)?,
+            interrupt: None,
+            registers: Vec::new(),
+            writes: Vec::new(),
+            condition_codes: 0,
+        };
+
+        while let Some(word) = words.next() {
+            if word == "INTERRUPT" && record.registers.is_empty() {
+                let vector = words.next().and_then(|vector| vector.strip_prefix("0x"));
+                let vector = vector.and_then(|vector| u8::from_str_radix(vector, 16).ok());
+                record.interrupt = Some(vector.ok_or_else(invalid)?);
+            } else if let Some(flags) = word.strip_prefix("CC=") {
+                record.condition_codes = flags.chars().fold(0, |codes, flag| match flag {
+                    'N' => codes | 4,
+                    'Z' => codes | 2,
+                    'P' => codes | 1,
+                    _ => codes,
+                });
+            } else if let Some((address, values)) = word
+                .strip_prefix('[')
+                .and_then(|write| write.split_once("]="))
+            {
+                let (old, value) = change(values)?;
+                record.writes.push(MemoryWrite {
+                    address: hex(address)?,
+                    old,
+                    value,
+                });
+            } else if let Some((register, values)) = word
+                .strip_prefix('R')
+                .and_then(|change| change.split_once('='))
+            {
+                let (old, new) = change(values)?;
+                record.registers.push(RegisterChange {
+                    register: register.parse().map_err(|_| invalid())?,
+                    old,
+                    new,
+                });
+            }
+        }
+
+        Ok(record)
+    }
+
+    /// Reads a record in the binary format, or `None` at the end of the
+    /// input.
+    pub fn read_binary(input: &mut impl Read) -> std::io::Result<Option<TraceRecord>> {
+        let mut first = [0; 1];
+        if input.read(&mut first)? == 0 {
+            return Ok(None);
+        }
+
+        let pc = u16::from_be_bytes([first[0], read_byte(input)?]);
+        let instruction = read_word(input)?;
+        let flags = read_byte(input)?;
+        let interrupt = if flags & INTERRUPT_FLAG != 0 {
+            Some(read_byte(input)?)
+        } else {
+            None
+        };
+
+        let mask = read_byte(input)?;
+        let mut registers = Vec::new();
+        for register in (0..8).filter(|register| mask & 1 << register != 0) {
+            registers.push(RegisterChange {
+                register,
+                old: read_word(input)?,
+                new: read_word(input)?,
+            });
+        }
+
+        let count = read_word(input)?;
+        let mut writes = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            writes.push(MemoryWrite {
+                address: read_word(input)?,
+                old: read_word(input)?,
+                value: read_word(input)?,
+            });
+        }
+
+        Ok(Some(TraceRecord {
+            pc,
+            instruction,
+            interrupt,
+            registers,
+            writes,
+            condition_codes: (flags & 0x7) as u16,
+        }))
+    }
+
+    /// The record in the binary format.
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend(self.pc.to_be_bytes());
+        bytes.extend(self.instruction.to_be_bytes());
+
+        let flags = (self.condition_codes & 0x7) as u8;
+        match self.interrupt {
+            Some(vector) => bytes.extend([flags | INTERRUPT_FLAG, vector]),
+            None => bytes.push(flags),
+        }
+
+        let mask = self
+            .registers
+            .iter()
+            .fold(0u8, |mask, change| mask | 1 << change.register);
+        bytes.push(mask);
+        for change in &self.registers {
+            bytes.extend(change.old.to_be_bytes());
+            bytes.extend(change.new.to_be_bytes());
+        }
+
+        let count = u16::try_from(self.writes.len()).expect("a step writes only a few words");
+        bytes.extend(count.to_be_bytes());
+        for write in &self.writes {
+            bytes.extend(write.address.to_be_bytes());
+            bytes.extend(write.old.to_be_bytes());
+            bytes.extend(write.value.to_be_bytes());
+        }
+
+        bytes
+    }
+}
+
+/// Writes the [`TraceRecord`] of every step to an output.
+///
+/// Once a write fails the tracer stops writing, and [`Tracer::finish`]
+/// reports the error.
+pub struct Tracer {
+    output: Box<dyn Write>,
+    format: TraceFormat,
+    error: Option<std::io::Error>,
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tracer({:?})", self.format)
+    }
+}
+
+impl Tracer {
+    /// Creates a tracer writing to `output`, starting with the header of the
+    /// binary format if that is the one used.
+    pub fn new(mut output: Box<dyn Write>, format: TraceFormat) -> std::io::Result<Tracer> {
+        if format == TraceFormat::Binary {
+            output.write_all(BINARY_MAGIC)?;
+            output.write_all(&[BINARY_VERSION])?;
+        }
+
+        Ok(Tracer {
+            output,
+            format,
+            error: None,
+        })
+    }
+
+    pub fn record(&mut self, record: &TraceRecord) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let result = match self.format {
+            TraceFormat::Text => writeln!(self.output, "{}", record.to_text()),
+            TraceFormat::Binary => self.output.write_all(&record.to_binary()),
+        };
+        self.error = result.err();
+    }
+
+    /// Flushes the output, reporting the first error writing the trace.
+    pub fn finish(mut self) -> std::io::Result<()> {
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => self.output.flush(),
+        }
+    }
+}
+
+/// Reads the records of a trace in either format, telling them apart by the
+/// header of the binary one.
+#[derive(Debug)]
+pub struct TraceReader<R: BufRead> {
+    input: R,
+    format: TraceFormat,
+    /// Line of the text trace read last
+    line: usize,
+}
+
+impl<R: BufRead> TraceReader<R> {
+    pub fn new(mut input: R) -> std::io::Result<TraceReader<R>> {
+        let binary = input.fill_buf()?.starts_with(BINARY_MAGIC);
+        if binary {
+            input.consume(BINARY_MAGIC.len());
+            let version = read_byte(&mut input)?;
+            if version != BINARY_VERSION {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Unsupported binary trace version {}", version),
+                ));
+            }
+        }
+
+        Ok(TraceReader {
+            input,
+            format: if binary {
+                TraceFormat::Binary
+            } else {
+                TraceFormat::Text
+            },
+            line: 0,
+        })
+    }
+
+    pub fn format(&self) -> TraceFormat {
+        self.format
+    }
+
+    fn read_text(&mut self) -> std::io::Result<Option<TraceRecord>> {
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            if self.input.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+
+            if !line.trim().is_empty() {
+                return TraceRecord::from_text(line.trim_end())
+                    .map(Some)
+                    .map_err(|error| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("line {}: {}", self.line, error),
+                        )
+                    });
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for TraceReader<R> {
+    type Item = std::io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.format {
+            TraceFormat::Text => self.read_text(),
+            TraceFormat::Binary => TraceRecord::read_binary(&mut self.input),
+        }
+        .transpose()
+    }
+}
+
+fn read_byte(input: &mut impl Read) -> std::io::Result<u8> {
+    let mut byte = [0; 1];
+    input.read_exact(&mut byte)?;
+
+    Ok(byte[0])
+}
+
+fn read_word(input: &mut impl Read) -> std::io::Result<u16> {
+    let mut word = [0; 2];
+    input.read_exact(&mut word)?;
+
+    Ok(u16::from_be_bytes(word))
+}
+
+/// E.g. `P`, or `-` when no flag is set.
+fn condition_codes(codes: u16) -> String {
+    let flags: String = [(4, 'N'), (2, 'Z'), (1, 'P')]
+        .iter()
+        .filter(|(bit, _)| codes & bit != 0)
+        .map(|(_, flag)| *flag)
+        .collect();
+
+    if flags.is_empty() {
+        "-".to_string()
+    } else {
+        flags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// ST R0 at 0x3001, writing 0x0001 to 0x3006.
+    fn store() -> TraceRecord {
+        TraceRecord {
+            pc: 0x3001,
+            instruction: 0x3004,
+            interrupt: None,
+            registers: Vec::new(),
+            writes: vec![MemoryWrite {
+                address: 0x3006,
+                old: 0x0000,
+                value: 0x0001,
+            }],
+            condition_codes: 0b001,
+        }
+    }
+
+    /// A store, a step changing a register and a timer interrupt pushing
+    /// the PSR and PC.
+    fn records() -> Vec<TraceRecord> {
+        vec![
+            store(),
+            TraceRecord {
+                pc: 0x3003,
+                instruction: 0xE003,
+                interrupt: None,
+                registers: vec![RegisterChange {
+                    register: 0,
+                    old: 0xFFFF,
+                    new: 0x3007,
+                }],
+                writes: Vec::new(),
+                condition_codes: 0b001,
+            },
+            TraceRecord {
+                pc: 0x3004,
+                instruction: 0x0FFF,
+                interrupt: Some(0x81),
+                registers: vec![RegisterChange {
+                    register: 6,
+                    old: 0xFE00,
+                    new: 0x2FFE,
+                }],
+                writes: vec![
+                    MemoryWrite {
+                        address: 0x2FFF,
+                        old: 0x0000,
+                        value: 0x8001,
+                    },
+                    MemoryWrite {
+                        address: 0x2FFE,
+                        old: 0x0000,
+                        value: 0x3004,
+                    },
+                ],
+                condition_codes: 0b001,
+            },
+        ]
+    }
+
+    /// Writes `records` with a [`Tracer`] in `format`, returning the bytes.
+    fn write(records: &[TraceRecord], format: TraceFormat) -> Vec<u8> {
+        let buffer = Rc::new(RefCell::new(Vec::new()));
+        let mut tracer = Tracer::new(Box::new(SharedBuffer(buffer.clone())), format).unwrap();
+        for record in records {
+            tracer.record(record);
+        }
+        tracer.finish().unwrap();
+
+        buffer.take()
+    }
+
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn text_records_parse_back() {
+        for record in records() {
+            assert_eq!(TraceRecord::from_text(&record.to_text()), Ok(record));
+        }
+    }
+
+    #[test]
+    fn text_traces_read_back() {
+        let bytes = write(&records(), TraceFormat::Text);
+        assert!(bytes.starts_with(b"3001  3004  ST R0"));
+
+        let reader = TraceReader::new(bytes.as_slice()).unwrap();
+        assert_eq!(reader.format(), TraceFormat::Text);
+        let read: Vec<TraceRecord> = reader.map(Result::unwrap).collect();
+        assert_eq!(read, records());
+    }
+
+    #[test]
+    fn binary_traces_read_back() {
+        let bytes = write(&records(), TraceFormat::Binary);
+        assert_eq!(&bytes[..5], b"LC3T\x02");
+
+        let reader = TraceReader::new(bytes.as_slice()).unwrap();
+        assert_eq!(reader.format(), TraceFormat::Binary);
+        let read: Vec<TraceRecord> = reader.map(Result::unwrap).collect();
+        assert_eq!(read, records());
+    }
+
+    #[test]
+    fn binary_traces_of_other_versions_are_rejected() {
+        let mut bytes = write(&records(), TraceFormat::Binary);
+        bytes[4] = 1;
+
+        let error = TraceReader::new(bytes.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn binary_records_keep_more_than_255_writes() {
+        let mut record = store();
+        record.writes = (0..300)
+            .map(|address| MemoryWrite {
+                address,
+                old: 0,
+                value: address,
+            })
+            .collect();
+
+        let mut bytes = record.to_binary();
+        bytes.extend(store().to_binary());
+        let mut input = bytes.as_slice();
+
+        assert_eq!(TraceRecord::read_binary(&mut input).unwrap(), Some(record));
+        assert_eq!(TraceRecord::read_binary(&mut input).unwrap(), Some(store()));
+        assert_eq!(TraceRecord::read_binary(&mut input).unwrap(), None);
+    }
+}
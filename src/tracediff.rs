@@ -0,0 +1,568 @@
+//! Compares two executions and finds where they first diverge.
+//!
+//! Executions are streams of [`TraceRecord`]s, read from trace files with a
+//! [`TraceReader`](crate::trace::TraceReader) or produced by running a
+//! program with [`execute`]. [`Alignment::Steps`] compares them record by
+//! record, which suits two runs of the same program. [`Alignment::Stores`]
+//! only compares the memory they write, in order, so that different programs
+//! computing the same thing, like a student's solution and the reference one,
+//! line up.
+
+use super::history::MemoryWrite;
+use super::trace::{TraceFormat, TraceRecord, Tracer};
+use super::vm::{StepOutcome, Vm};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::io::Write;
+use std::rc::Rc;
+
+/// How many records before the divergence are kept as context.
+const CONTEXT_LENGTH: usize = 5;
+/// How many differing words of memory are reported.
+const MEMORY_DIFFERENCES: usize = 16;
+
+/// How the records of two executions are matched up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    /// Every record has to match
+    Steps,
+    /// Only the addresses and values of the memory writes have to match
+    Stores,
+}
+
+/// One of the executions where they diverged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Execution {
+    /// Records read, including the divergent one
+    pub steps: usize,
+    /// The divergent record, `None` if the execution ended instead
+    pub record: Option<TraceRecord>,
+    /// The records right before it, oldest first
+    pub context: Vec<TraceRecord>,
+    /// Registers after the divergent record, `None` for the ones the trace
+    /// never changed
+    pub registers: [Option<u16>; 8],
+}
+
+/// A word of memory holding different values in the two executions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryDifference {
+    pub address: u16,
+    /// Value last written by the expected execution, `None` if it never
+    /// wrote the address
+    pub expected: Option<u16>,
+    /// Value last written by the actual execution
+    pub actual: Option<u16>,
+}
+
+/// The error a program run by [`execute`] yields when it reaches its step
+/// limit, which [`diff`] passes on: the executions matched up to there, but
+/// whether they diverge later is unknown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Truncated {
+    /// Steps run before the limit
+    pub steps: u64,
+}
+
+impl Truncated {
+    /// The [`Truncated`] that `error` carries, if it is one.
+    pub fn from_error(error: &std::io::Error) -> Option<&Truncated> {
+        error.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for Truncated {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the program was cut off after {} steps", self.steps)
+    }
+}
+
+impl std::error::Error for Truncated {}
+
+/// Where two executions first differ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub alignment: Alignment,
+    /// Records, or stores, that matched before
+    pub matching: usize,
+    pub expected: Execution,
+    pub actual: Execution,
+    /// Memory the executions wrote different values to, lowest address first
+    pub memory: Vec<MemoryDifference>,
+}
+
+/// Compares the `expected` execution to the `actual` one, returning where
+/// they first diverge or `None` if they match up to the end. Errors reading
+/// either of them, including a [`Truncated`] run, are returned as they come.
+pub fn diff<E, A>(
+    expected: E,
+    actual: A,
+    alignment: Alignment,
+) -> std::io::Result<Option<Divergence>>
+where
+    E: Iterator<Item = std::io::Result<TraceRecord>>,
+    A: Iterator<Item = std::io::Result<TraceRecord>>,
+{
+    let mut expected = Side::new(expected);
+    let mut actual = Side::new(actual);
+    let mut matching = 0;
+
+    loop {
+        let same = match alignment {
+            Alignment::Steps => {
+                let (expected, actual) = (expected.advance()?, actual.advance()?);
+                if expected.is_none() && actual.is_none() {
+                    return Ok(None);
+                }
+                expected == actual
+            }
+            Alignment::Stores => match (expected.next_store()?, actual.next_store()?) {
+                (None, None) => return Ok(None),
+                (Some(expected), Some(actual)) => {
+                    expected.address == actual.address && expected.value == actual.value
+                }
+                _ => false,
+            },
+        };
+
+        if !same {
+            let memory = memory_differences(&expected.memory, &actual.memory);
+            return Ok(Some(Divergence {
+                alignment,
+                matching,
+                expected: expected.into_execution(),
+                actual: actual.into_execution(),
+                memory,
+            }));
+        }
+        matching += 1;
+    }
+}
+
+/// Runs `vm` and yields the record of every step until the program stops. A
+/// program still running after `max_steps` steps yields a [`Truncated`]
+/// error instead of its next record. The VM should read its input from a
+/// [`MemoryConsole`](crate::console::MemoryConsole) created with
+/// [`MemoryConsole::new`](crate::console::MemoryConsole::new), so that it never
+/// waits for a key.
+pub fn execute(
+    mut vm: Vm,
+    max_steps: Option<u64>,
+) -> impl Iterator<Item = std::io::Result<TraceRecord>> {
+    let buffer = Rc::new(RefCell::new(Vec::new()));
+    let tracer = Tracer::new(Box::new(SharedBuffer(buffer.clone())), TraceFormat::Binary)
+        .expect("writing to memory does not fail");
+    vm.set_tracer(tracer);
+    buffer.borrow_mut().clear();
+
+    ProgramTrace {
+        vm,
+        buffer,
+        steps: 0,
+        max_steps,
+        stopped: false,
+    }
+}
+
+/// The records of a running program, see [`execute`].
+struct ProgramTrace {
+    vm: Vm,
+    /// What the tracer wrote since the last record was read
+    buffer: Rc<RefCell<Vec<u8>>>,
+    steps: u64,
+    max_steps: Option<u64>,
+    stopped: bool,
+}
+
+impl Iterator for ProgramTrace {
+    type Item = std::io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.stopped {
+            if self.max_steps.is_some_and(|max| self.steps >= max) {
+                self.stopped = true;
+                let truncated = Truncated { steps: self.steps };
+                return Some(Err(std::io::Error::other(truncated)));
+            }
+
+            self.stopped = matches!(
+                self.vm.step(),
+                StepOutcome::Halted | StepOutcome::EndOfInput | StepOutcome::Faulted(_)
+            );
+
+            let bytes = std::mem::take(&mut *self.buffer.borrow_mut());
+            if !bytes.is_empty() {
+                self.steps += 1;
+                return TraceRecord::read_binary(&mut bytes.as_slice()).transpose();
+            }
+        }
+
+        None
+    }
+}
+
+/// Collects what a tracer writes, so the records can be read back as they
+/// come.
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An execution being compared, along with the registers and memory its
+/// records add up to.
+struct Side<I> {
+    records: I,
+    steps: usize,
+    /// The record read last
+    current: Option<TraceRecord>,
+    context: VecDeque<TraceRecord>,
+    ended: bool,
+    registers: [Option<u16>; 8],
+    memory: BTreeMap<u16, u16>,
+    /// Writes of the current record not compared yet
+    pending: VecDeque<MemoryWrite>,
+}
+
+impl<I: Iterator<Item = std::io::Result<TraceRecord>>> Side<I> {
+    fn new(records: I) -> Side<I> {
+        Side {
+            records,
+            steps: 0,
+            current: None,
+            context: VecDeque::new(),
+            ended: false,
+            registers: [None; 8],
+            memory: BTreeMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Reads the next record, or `None` at the end of the execution.
+    fn advance(&mut self) -> std::io::Result<Option<TraceRecord>> {
+        let record = self.records.next().transpose()?;
+
+        if let Some(previous) = self.current.take() {
+            if self.context.len() == CONTEXT_LENGTH {
+                self.context.pop_front();
+            }
+            self.context.push_back(previous);
+        }
+        let Some(record) = record else {
+            self.ended = true;
+            return Ok(None);
+        };
+
+        for change in &record.registers {
+            self.registers[change.register as usize] = Some(change.new);
+        }
+        for write in &record.writes {
+            self.memory.insert(write.address, write.value);
+        }
+        self.steps += 1;
+        self.current = Some(record.clone());
+
+        Ok(Some(record))
+    }
+
+    /// The next memory write, reading records until one writes memory.
+    fn next_store(&mut self) -> std::io::Result<Option<MemoryWrite>> {
+        loop {
+            if let Some(write) = self.pending.pop_front() {
+                return Ok(Some(write));
+            }
+
+            match self.advance()? {
+                Some(record) => self.pending.extend(record.writes),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn into_execution(self) -> Execution {
+        Execution {
+            steps: self.steps,
+            record: if self.ended { None } else { self.current },
+            context: self.context.into(),
+            registers: self.registers,
+        }
+    }
+}
+
+fn memory_differences(
+    expected: &BTreeMap<u16, u16>,
+    actual: &BTreeMap<u16, u16>,
+) -> Vec<MemoryDifference> {
+    let mut addresses: Vec<u16> = expected.keys().chain(actual.keys()).copied().collect();
+    addresses.sort_unstable();
+    addresses.dedup();
+
+    addresses
+        .into_iter()
+        .map(|address| MemoryDifference {
+            address,
+            expected: expected.get(&address).copied(),
+            actual: actual.get(&address).copied(),
+        })
+        .filter(|difference| difference.expected != difference.actual)
+        .take(MEMORY_DIFFERENCES)
+        .collect()
+}
+
+/// A value, or `????` when it is not known.
+fn word(value: Option<u16>) -> String {
+    value.map_or("????".to_string(), |value| format!("{:04X}", value))
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let aligned = match self.alignment {
+            Alignment::Steps => "steps",
+            Alignment::Stores => "stores",
+        };
+        writeln!(
+            f,
+            "The executions diverge after {} matching {}",
+            self.matching, aligned
+        )?;
+
+        let executions = [("Expected", &self.expected), ("Actual", &self.actual)];
+        for (name, execution) in executions {
+            writeln!(f)?;
+            match &execution.record {
+                Some(record) => writeln!(
+                    f,
+                    "{}, step {}:\n  {}",
+                    name,
+                    execution.steps,
+                    record.to_text()
+                )?,
+                None => writeln!(f, "{}: ended after {} steps", name, execution.steps)?,
+            }
+        }
+
+        let registers: Vec<usize> = (0..8)
+            .filter(|index| self.expected.registers[*index] != self.actual.registers[*index])
+            .collect();
+        if !registers.is_empty() {
+            writeln!(f, "\nRegisters that differ:")?;
+            for index in registers {
+                writeln!(
+                    f,
+                    "  R{}  expected {}  actual {}",
+                    index,
+                    word(self.expected.registers[index]),
+                    word(self.actual.registers[index])
+                )?;
+            }
+        }
+
+        if !self.memory.is_empty() {
+            writeln!(f, "\nMemory that differs:")?;
+            for difference in &self.memory {
+                writeln!(
+                    f,
+                    "  {:04X}  expected {}  actual {}",
+                    difference.address,
+                    word(difference.expected),
+                    word(difference.actual)
+                )?;
+            }
+        }
+
+        for (name, execution) in executions {
+            if !execution.context.is_empty() {
+                writeln!(f, "\n{} before that:", name)?;
+                for record in &execution.context {
+                    writeln!(f, "  {}", record.to_text())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::MemoryConsole;
+    use crate::trace::RegisterChange;
+
+    /// A step at `pc` writing `value` to `address`, if any.
+    fn step(pc: u16, store: Option<(u16, u16)>) -> TraceRecord {
+        TraceRecord {
+            pc,
+            instruction: if store.is_some() { 0x3000 } else { 0x1021 },
+            interrupt: None,
+            registers: match store {
+                Some(_) => Vec::new(),
+                None => vec![RegisterChange {
+                    register: 0,
+                    old: pc - 1,
+                    new: pc,
+                }],
+            },
+            writes: store
+                .map(|(address, value)| MemoryWrite {
+                    address,
+                    old: 0,
+                    value,
+                })
+                .into_iter()
+                .collect(),
+            condition_codes: 0b001,
+        }
+    }
+
+    fn records(records: &[TraceRecord]) -> impl Iterator<Item = std::io::Result<TraceRecord>> + '_ {
+        records.iter().cloned().map(Ok)
+    }
+
+    fn vm(image: &[u16]) -> Vm {
+        let mut vm = Vm::with_console(Box::new(MemoryConsole::new(&[])));
+        vm.load_image(image).unwrap();
+        vm
+    }
+
+    #[test]
+    fn steps_match_up_to_the_end() {
+        let trace = [step(0x3001, None), step(0x3002, Some((0x4000, 1)))];
+
+        let divergence = diff(records(&trace), records(&trace), Alignment::Steps).unwrap();
+        assert_eq!(divergence, None);
+    }
+
+    #[test]
+    fn steps_diverge_at_the_first_different_record() {
+        let expected = [
+            step(0x3001, None),
+            step(0x3002, Some((0x4000, 1))),
+            step(0x3003, None),
+        ];
+        let actual = [
+            step(0x3001, None),
+            step(0x3002, Some((0x4000, 2))),
+            step(0x3003, None),
+        ];
+
+        let divergence = diff(records(&expected), records(&actual), Alignment::Steps)
+            .unwrap()
+            .unwrap();
+        assert_eq!(divergence.matching, 1);
+        assert_eq!(divergence.expected.steps, 2);
+        assert_eq!(divergence.expected.record, Some(expected[1].clone()));
+        assert_eq!(divergence.actual.record, Some(actual[1].clone()));
+        assert_eq!(divergence.expected.context, vec![expected[0].clone()]);
+        assert_eq!(divergence.expected.registers[0], Some(0x3001));
+        assert_eq!(
+            divergence.memory,
+            vec![MemoryDifference {
+                address: 0x4000,
+                expected: Some(1),
+                actual: Some(2),
+            }]
+        );
+    }
+
+    #[test]
+    fn steps_diverge_when_one_execution_ends_first() {
+        let expected = [step(0x3001, None), step(0x3002, None)];
+
+        let divergence = diff(
+            records(&expected),
+            records(&expected[..1]),
+            Alignment::Steps,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(divergence.matching, 1);
+        assert_eq!(divergence.expected.record, Some(expected[1].clone()));
+        assert_eq!(divergence.actual.record, None);
+        assert_eq!(divergence.actual.steps, 1);
+    }
+
+    #[test]
+    fn stores_match_whatever_the_other_steps() {
+        let expected = [
+            step(0x3001, None),
+            step(0x3002, Some((0x4000, 1))),
+            step(0x3003, Some((0x4001, 2))),
+        ];
+        let actual = [
+            step(0x5001, Some((0x4000, 1))),
+            step(0x5002, None),
+            step(0x5003, None),
+            step(0x5004, Some((0x4001, 2))),
+        ];
+
+        let divergence = diff(records(&expected), records(&actual), Alignment::Stores).unwrap();
+        assert_eq!(divergence, None);
+    }
+
+    #[test]
+    fn stores_diverge_at_the_first_different_store() {
+        let expected = [
+            step(0x3001, Some((0x4000, 1))),
+            step(0x3002, Some((0x4001, 2))),
+        ];
+        let actual = [
+            step(0x5001, Some((0x4000, 1))),
+            step(0x5002, None),
+            step(0x5003, Some((0x4001, 3))),
+        ];
+
+        let divergence = diff(records(&expected), records(&actual), Alignment::Stores)
+            .unwrap()
+            .unwrap();
+        assert_eq!(divergence.matching, 1);
+        assert_eq!(divergence.expected.record, Some(expected[1].clone()));
+        assert_eq!(divergence.actual.record, Some(actual[2].clone()));
+        assert_eq!(divergence.actual.steps, 3);
+    }
+
+    #[test]
+    fn stores_diverge_when_one_execution_stops_storing() {
+        let expected = [step(0x3001, Some((0x4000, 1)))];
+        let actual = [step(0x5001, None)];
+
+        let divergence = diff(records(&expected), records(&actual), Alignment::Stores)
+            .unwrap()
+            .unwrap();
+        assert_eq!(divergence.matching, 0);
+        assert_eq!(divergence.actual.record, None);
+    }
+
+    #[test]
+    fn execute_yields_every_step_until_the_program_halts() {
+        let vm = vm(&[0x3000, 0x1021, 0x1021, 0xF025]);
+
+        let trace: Vec<TraceRecord> = execute(vm, Some(10)).map(Result::unwrap).collect();
+        assert_eq!(trace.len(), 3);
+        assert_eq!(trace[1].pc, 0x3001);
+    }
+
+    #[test]
+    fn runs_cut_off_by_the_step_limit_are_not_treated_as_matching() {
+        let spin = [0x3000, 0x0FFF];
+
+        let error = diff(
+            execute(vm(&spin), Some(20)),
+            execute(vm(&spin), Some(20)),
+            Alignment::Steps,
+        )
+        .unwrap_err();
+        assert_eq!(
+            Truncated::from_error(&error),
+            Some(&Truncated { steps: 20 })
+        );
+    }
+}
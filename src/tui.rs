@@ -0,0 +1,647 @@
+//! A full-screen debugger for the terminal, drawn with ANSI escape codes.
+//!
+//! The screen shows the registers and condition codes, the disassembly around
+//! the PC, a memory view, the breakpoints and what the program printed. While
+//! the program is paused, keys drive the debugger (the bottom line lists
+//! them); while it runs, they are typed into the program and Ctrl-C pauses it
+//! again.
+
+use super::console::MemoryConsole;
+use super::disassembler::disassemble_instruction;
+use super::state::Privilege;
+use super::vm::{StepOutcome, Vm};
+use nix::sys::select::{select, FdSet};
+use nix::sys::time::{TimeVal, TimeValLike};
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::time::{Duration, Instant};
+use termios::*;
+
+const HELP: &str = "s step  n next  r back  c continue  ^C pause  j/k move  b break  [/] memory  g goto  i key  q quit";
+/// How many steps are recorded for stepping back.
+const HISTORY_CAPACITY: usize = 1 << 20;
+/// How long the program runs between redraws.
+const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+/// How many instructions run between checks of the clock.
+const CLOCK_CHECK_INTERVAL: u32 = 1024;
+/// How long to wait for a key before redrawing anyway, e.g. after a resize.
+const KEY_TIMEOUT: Duration = Duration::from_millis(250);
+/// How many lines of the program's output are kept.
+const OUTPUT_LINES: usize = 500;
+/// How many words `[` and `]` scroll the memory view by.
+const MEMORY_SCROLL: u16 = 0x40;
+/// Width of the registers and disassembly panes.
+const LEFT_WIDTH: usize = 42;
+const CTRL_C: u8 = 3;
+const ESCAPE: u8 = 0x1B;
+
+/// What keys do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Mode {
+    Paused,
+    /// Keys go to the program. Running stops at a breakpoint, when the program
+    /// stops, or when the PC reaches the given address
+    Running {
+        until: Option<u16>,
+    },
+    /// Typing the address to show in the memory view
+    Goto(String),
+    /// The next key goes to the program
+    Key,
+}
+
+/// A key read from the terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Byte(u8),
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+}
+
+/// Where the escape sequences in the program's output have got to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Escape {
+    None,
+    Start,
+    /// Parameters of a control sequence so far
+    Csi(String),
+}
+
+/// A pane line, optionally shown in reverse video.
+struct Line {
+    text: String,
+    highlight: bool,
+}
+
+impl From<String> for Line {
+    fn from(text: String) -> Line {
+        Line {
+            text,
+            highlight: false,
+        }
+    }
+}
+
+/// Debugs a [`Vm`] full-screen.
+#[derive(Debug)]
+pub struct Tui {
+    vm: Vm,
+    console: MemoryConsole,
+    breakpoints: BTreeSet<u16>,
+    mode: Mode,
+    /// Why the program last stopped, or what went wrong
+    status: String,
+    /// Selected line of the disassembly
+    cursor: u16,
+    /// First address of the memory view
+    memory_start: u16,
+    /// What the program printed, without the escape sequences
+    output: String,
+    escape: Escape,
+}
+
+impl Tui {
+    /// Takes over `vm`, replacing its console so that what the program prints
+    /// ends up in the output pane.
+    pub fn new(mut vm: Vm) -> Tui {
+        let console = MemoryConsole::interactive();
+        vm.state_mut().console = Box::new(console.clone());
+        vm.record_history(HISTORY_CAPACITY);
+        let pc = vm.state().pc;
+
+        Tui {
+            vm,
+            console,
+            breakpoints: BTreeSet::new(),
+            mode: Mode::Paused,
+            status: String::new(),
+            cursor: pc,
+            memory_start: pc & !0x7,
+            output: String::new(),
+            escape: Escape::None,
+        }
+    }
+
+    /// Takes over the terminal until `q` is pressed or stdin ends.
+    pub fn run(&mut self) -> std::io::Result<()> {
+        let _terminal = RawTerminal::enter()?;
+
+        loop {
+            self.collect_output();
+            self.draw()?;
+
+            let timeout = match self.mode {
+                Mode::Running { .. } => Duration::ZERO,
+                _ => KEY_TIMEOUT,
+            };
+            let Some(input) = read_input(timeout)? else {
+                return Ok(());
+            };
+
+            if let Mode::Running { until } = self.mode {
+                for byte in input {
+                    if byte == CTRL_C {
+                        self.pause("Paused".to_string());
+                    } else {
+                        self.console.push_input(&[byte]);
+                    }
+                }
+                if self.mode != Mode::Paused {
+                    self.run_frame(until);
+                }
+            } else {
+                for key in parse_keys(&input) {
+                    if !self.handle_key(key) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handles a key while the program is paused, returning `false` to quit.
+    fn handle_key(&mut self, key: Key) -> bool {
+        match (&mut self.mode, key) {
+            (Mode::Goto(address), Key::Byte(byte)) => match byte {
+                b'\r' | b'\n' => {
+                    if let Ok(start) = u16::from_str_radix(address, 16) {
+                        self.memory_start = start;
+                    }
+                    self.mode = Mode::Paused;
+                }
+                0x7F | 0x08 => {
+                    address.pop();
+                }
+                ESCAPE | CTRL_C => self.mode = Mode::Paused,
+                _ if byte.is_ascii_hexdigit() && address.len() < 4 => {
+                    address.push(byte.to_ascii_uppercase() as char)
+                }
+                _ => {}
+            },
+            (Mode::Key, Key::Byte(byte)) => {
+                self.console.push_input(&[byte]);
+                self.mode = Mode::Paused;
+            }
+            (Mode::Goto(_) | Mode::Key, _) => {}
+            (_, Key::Byte(b's' | b' ')) => self.step(),
+            (_, Key::Byte(b'n')) => self.next(),
+            (_, Key::Byte(b'r')) => self.step_back(),
+            (_, Key::Byte(b'c')) => self.resume(None),
+            (_, Key::Byte(b'j') | Key::Down) => self.cursor = self.cursor.wrapping_add(1),
+            (_, Key::Byte(b'k') | Key::Up) => self.cursor = self.cursor.wrapping_sub(1),
+            (_, Key::Byte(b'b')) => self.toggle_breakpoint(),
+            (_, Key::Byte(b']') | Key::PageDown) => {
+                self.memory_start = self.memory_start.wrapping_add(MEMORY_SCROLL)
+            }
+            (_, Key::Byte(b'[') | Key::PageUp) => {
+                self.memory_start = self.memory_start.wrapping_sub(MEMORY_SCROLL)
+            }
+            (_, Key::Byte(b'g')) => self.mode = Mode::Goto(String::new()),
+            (_, Key::Byte(b'i')) => self.mode = Mode::Key,
+            (_, Key::Byte(b'q')) => return false,
+            _ => {}
+        }
+
+        true
+    }
+
+    fn toggle_breakpoint(&mut self) {
+        if !self.breakpoints.remove(&self.cursor) {
+            self.breakpoints.insert(self.cursor);
+        }
+    }
+
+    fn step(&mut self) {
+        let status = match self.vm.step() {
+            StepOutcome::Executed
+            | StepOutcome::Trapped(_)
+            | StepOutcome::Exception(_)
+            | StepOutcome::Interrupted(_) => String::new(),
+            StepOutcome::WaitingForInput => {
+                "The program is waiting for a key, press i to type one".to_string()
+            }
+            StepOutcome::Halted => "Program halted".to_string(),
+            StepOutcome::EndOfInput => "Program ran out of input".to_string(),
+            StepOutcome::Faulted(error) => error.to_string(),
+        };
+
+        self.pause(status);
+    }
+
+    /// Steps over subroutine calls and traps, or steps any other instruction.
+    fn next(&mut self) {
+        let pc = self.vm.state().pc;
+        let opcode = self.vm.state().memory[pc as usize] >> 12;
+
+        match opcode {
+            0x4 | 0xF => self.resume(Some(pc.wrapping_add(1))),
+            _ => self.step(),
+        }
+    }
+
+    fn step_back(&mut self) {
+        let status = if self.vm.step_back() {
+            String::new()
+        } else {
+            "Reached the start of the history".to_string()
+        };
+
+        self.pause(status);
+    }
+
+    fn resume(&mut self, until: Option<u16>) {
+        self.status.clear();
+        self.mode = Mode::Running { until };
+    }
+
+    fn pause(&mut self, status: String) {
+        self.status = status;
+        self.mode = Mode::Paused;
+        self.cursor = self.vm.state().pc;
+    }
+
+    /// Runs the program for a frame or until it has to stop. The instruction
+    /// at the PC always runs, even if it has a breakpoint.
+    fn run_frame(&mut self, until: Option<u16>) {
+        let start = Instant::now();
+        let mut executed: u32 = 0;
+
+        loop {
+            match self.vm.step() {
+                StepOutcome::Executed
+                | StepOutcome::Trapped(_)
+                | StepOutcome::Exception(_)
+                | StepOutcome::Interrupted(_) => {}
+                // Give the terminal a moment to deliver the key
+                StepOutcome::WaitingForInput => {
+                    std::thread::sleep(FRAME_INTERVAL / 4);
+                    return;
+                }
+                StepOutcome::Halted => return self.pause("Program halted".to_string()),
+                StepOutcome::EndOfInput => {
+                    return self.pause("Program ran out of input".to_string())
+                }
+                StepOutcome::Faulted(error) => return self.pause(error.to_string()),
+            }
+
+            let pc = self.vm.state().pc;
+            if until == Some(pc) {
+                return self.pause(String::new());
+            }
+            if self.breakpoints.contains(&pc) {
+                return self.pause(format!("Breakpoint at 0x{:04X}", pc));
+            }
+
+            executed = executed.wrapping_add(1);
+            if executed.is_multiple_of(CLOCK_CHECK_INTERVAL) && start.elapsed() >= FRAME_INTERVAL {
+                return;
+            }
+        }
+    }
+
+    /// Moves what the program printed to the output pane. Clearing the screen
+    /// clears the pane, other escape sequences are dropped.
+    fn collect_output(&mut self) {
+        for byte in self.console.take_output() {
+            self.escape = match (std::mem::replace(&mut self.escape, Escape::None), byte) {
+                (Escape::None, ESCAPE) => Escape::Start,
+                (Escape::None, b'\n' | b' '..=b'~') => {
+                    self.output.push(byte as char);
+                    Escape::None
+                }
+                (Escape::None, _) => Escape::None,
+                (Escape::Start, b'[') => Escape::Csi(String::new()),
+                (Escape::Start, _) => Escape::None,
+                (Escape::Csi(parameters), b'@'..=b'~') => {
+                    if byte == b'J' && parameters == "2" {
+                        self.output.clear();
+                    }
+                    Escape::None
+                }
+                (Escape::Csi(mut parameters), _) => {
+                    parameters.push(byte as char);
+                    Escape::Csi(parameters)
+                }
+            };
+        }
+
+        let lines = self.output.matches('\n').count();
+        if lines > OUTPUT_LINES {
+            let (cut, _) = self
+                .output
+                .match_indices('\n')
+                .nth(lines - OUTPUT_LINES)
+                .expect("counted the lines");
+            self.output.drain(..=cut);
+        }
+    }
+
+    fn draw(&self) -> std::io::Result<()> {
+        let (width, height) = terminal_size();
+        let width = width.max(LEFT_WIDTH + 30);
+        let body = height.saturating_sub(2).max(20);
+        let output_height = (body / 3).max(5);
+        let upper = body - output_height;
+        let right_width = width - LEFT_WIDTH;
+
+        let mut left = boxed("Registers", self.registers(), LEFT_WIDTH, 6);
+        left.extend(boxed(
+            "Disassembly",
+            self.disassembly(upper - 8),
+            LEFT_WIDTH,
+            upper - 6,
+        ));
+        let mut right = boxed(
+            "Memory",
+            self.memory(upper - 8, right_width - 2),
+            right_width,
+            upper - 6,
+        );
+        right.extend(boxed(
+            "Breakpoints",
+            self.breakpoint_lines(4),
+            right_width,
+            6,
+        ));
+
+        let mode = match &self.mode {
+            Mode::Paused => "paused".to_string(),
+            Mode::Running { .. } => "running, Ctrl-C pauses".to_string(),
+            Mode::Goto(address) => format!("go to address: {}_", address),
+            Mode::Key => "press a key for the program".to_string(),
+        };
+        let title = format!(" LC-3 debugger | {} | {}", mode, self.status);
+
+        let mut screen = String::from("\x1b[H");
+        screen.push_str(&highlight(&fit(&title, width)));
+        screen.push_str("\r\n");
+        for (left, right) in left.iter().zip(&right) {
+            screen.push_str(left);
+            screen.push_str(right);
+            screen.push_str("\r\n");
+        }
+        for line in boxed(
+            "Output",
+            self.output_lines(output_height - 2),
+            width,
+            output_height,
+        ) {
+            screen.push_str(&line);
+            screen.push_str("\r\n");
+        }
+        screen.push_str(&fit(HELP, width));
+
+        let mut stdout = std::io::stdout();
+        stdout.write_all(screen.as_bytes())?;
+        stdout.flush()
+    }
+
+    fn registers(&self) -> Vec<Line> {
+        let state = self.vm.state();
+        let registers = |range: std::ops::Range<usize>| {
+            range
+                .map(|index| format!("R{} {:04X}", index, state.registers[index]))
+                .collect::<Vec<_>>()
+                .join("  ")
+        };
+
+        let codes = state.condition_codes();
+        let flags: String = [(4, 'N'), (2, 'Z'), (1, 'P')]
+            .iter()
+            .map(|(bit, flag)| if codes & bit != 0 { *flag } else { '-' })
+            .collect();
+        let privilege = match state.privilege() {
+            Privilege::Supervisor => "Supervisor",
+            Privilege::User => "User",
+        };
+
+        vec![
+            registers(0..4).into(),
+            registers(4..8).into(),
+            format!("PC {:04X}  PSR {:04X}  CC {}", state.pc, state.psr, flags).into(),
+            format!("{} mode, priority {}", privilege, state.priority()).into(),
+        ]
+    }
+
+    /// The instructions around the cursor, marking the PC with `=>` and
+    /// breakpoints with `*`.
+    fn disassembly(&self, count: usize) -> Vec<Line> {
+        let state = self.vm.state();
+        let start = self.cursor.saturating_sub(count as u16 / 3);
+
+        (start..=u16::MAX)
+            .take(count)
+            .map(|address| {
+                let instruction = state.memory[address as usize];
+                let pc = if address == state.pc { "=>" } else { "  " };
+                let breakpoint = if self.breakpoints.contains(&address) {
+                    "*"
+                } else {
+                    " "
+                };
+
+                Line {
+                    text: format!(
+                        "{}{} {:04X}  {:04X}  {}",
+                        pc,
+                        breakpoint,
+                        address,
+                        instruction,
+                        disassemble_instruction(instruction, address)
+                    ),
+                    highlight: address == self.cursor,
+                }
+            })
+            .collect()
+    }
+
+    /// Memory as it is stored, in hex and as ASCII, fitting as many words to
+    /// a row as `width` allows.
+    fn memory(&self, rows: usize, width: usize) -> Vec<Line> {
+        let memory = &self.vm.state().memory;
+        let row_length: u16 = if width >= 6 + 8 * 6 { 8 } else { 4 };
+
+        (0..rows as u16)
+            .map(|row| {
+                let start = self.memory_start.wrapping_add(row * row_length);
+                let words: Vec<u16> = (0..row_length)
+                    .map(|offset| memory[start.wrapping_add(offset) as usize])
+                    .collect();
+                let hex: Vec<String> = words.iter().map(|word| format!("{:04X}", word)).collect();
+                let ascii: String = words
+                    .iter()
+                    .map(|word| match *word {
+                        0x20..=0x7E => *word as u8 as char,
+                        _ => '.',
+                    })
+                    .collect();
+
+                format!("{:04X}  {}  {}", start, hex.join(" "), ascii).into()
+            })
+            .collect()
+    }
+
+    fn breakpoint_lines(&self, count: usize) -> Vec<Line> {
+        if self.breakpoints.is_empty() {
+            return vec!["None, b sets one at the selected line".to_string().into()];
+        }
+
+        let memory = &self.vm.state().memory;
+        let mut lines: Vec<Line> = self
+            .breakpoints
+            .iter()
+            .take(count)
+            .map(|address| {
+                let instruction = memory[*address as usize];
+                format!(
+                    "{:04X}  {}",
+                    address,
+                    disassemble_instruction(instruction, *address)
+                )
+                .into()
+            })
+            .collect();
+        if self.breakpoints.len() > count {
+            lines.pop();
+            lines.push(format!("and {} more", self.breakpoints.len() - count + 1).into());
+        }
+
+        lines
+    }
+
+    /// The last `count` lines the program printed.
+    fn output_lines(&self, count: usize) -> Vec<Line> {
+        let lines: Vec<&str> = self.output.split('\n').collect();
+
+        lines[lines.len().saturating_sub(count)..]
+            .iter()
+            .map(|line| line.to_string().into())
+            .collect()
+    }
+}
+
+/// Puts the terminal in raw mode on the alternate screen until dropped.
+struct RawTerminal {
+    original: Termios,
+}
+
+impl RawTerminal {
+    fn enter() -> std::io::Result<RawTerminal> {
+        let original = Termios::from_fd(0)?;
+        let mut raw = original;
+        raw.c_lflag &= !(ICANON | ECHO | ISIG);
+        tcsetattr(0, TCSANOW, &raw)?;
+
+        let mut stdout = std::io::stdout();
+        stdout.write_all(b"\x1b[?1049h\x1b[?25l\x1b[2J")?;
+        stdout.flush()?;
+
+        Ok(RawTerminal { original })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(b"\x1b[?25h\x1b[?1049l");
+        let _ = stdout.flush();
+        let _ = tcsetattr(0, TCSANOW, &self.original);
+    }
+}
+
+/// Draws `lines` in a box with `title`, `width` columns wide and `height`
+/// rows high.
+fn boxed(title: &str, lines: Vec<Line>, width: usize, height: usize) -> Vec<String> {
+    let inner = width.saturating_sub(2);
+    let mut rows = vec![format!(
+        "┌{}┐",
+        fit(&format!("─ {} ", title), inner).replace(' ', "─")
+    )];
+
+    rows.extend(
+        lines
+            .into_iter()
+            .chain(std::iter::repeat_with(|| String::new().into()))
+            .take(height.saturating_sub(2))
+            .map(|line| {
+                let text = fit(&line.text, inner);
+                if line.highlight {
+                    format!("│{}│", highlight(&text))
+                } else {
+                    format!("│{}│", text)
+                }
+            }),
+    );
+    rows.push(format!("└{}┘", "─".repeat(inner)));
+
+    rows
+}
+
+/// Truncates or pads `text` to exactly `width` columns.
+fn fit(text: &str, width: usize) -> String {
+    let mut fitted: String = text.chars().take(width).collect();
+    let length = fitted.chars().count();
+    fitted.extend(std::iter::repeat_n(' ', width - length));
+
+    fitted
+}
+
+fn highlight(text: &str) -> String {
+    format!("\x1b[7m{}\x1b[0m", text)
+}
+
+/// The terminal's width and height, or 80 by 24 if it can't tell.
+fn terminal_size() -> (usize, usize) {
+    // SAFETY: TIOCGWINSZ only writes the winsize it is given.
+    let mut size: nix::libc::winsize = unsafe { std::mem::zeroed() };
+    let result = unsafe { nix::libc::ioctl(1, nix::libc::TIOCGWINSZ, &mut size) };
+
+    if result == 0 && size.ws_col > 0 && size.ws_row > 0 {
+        (size.ws_col as usize, size.ws_row as usize)
+    } else {
+        (80, 24)
+    }
+}
+
+/// Waits up to `timeout` for input and reads what is there. Returns `None` at
+/// the end of the input.
+fn read_input(timeout: Duration) -> std::io::Result<Option<Vec<u8>>> {
+    let mut readfds = FdSet::new();
+    readfds.insert(0);
+    let mut timeout = TimeVal::microseconds(timeout.as_micros() as i64);
+
+    match select(1, &mut readfds, None, None, &mut timeout) {
+        Ok(0) | Err(nix::errno::Errno::EINTR) => return Ok(Some(Vec::new())),
+        Ok(_) => {}
+        Err(error) => return Err(error.into()),
+    }
+
+    let mut buffer = [0; 64];
+    match nix::unistd::read(0, &mut buffer)? {
+        0 => Ok(None),
+        count => Ok(Some(buffer[..count].to_vec())),
+    }
+}
+
+/// Splits input into keys, recognizing the escape sequences of the arrow and
+/// page keys.
+fn parse_keys(input: &[u8]) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let mut rest = input;
+
+    while let Some((byte, tail)) = rest.split_first() {
+        let (key, length) = match (*byte, tail) {
+            (ESCAPE, [b'[', b'A', ..]) => (Key::Up, 3),
+            (ESCAPE, [b'[', b'B', ..]) => (Key::Down, 3),
+            (ESCAPE, [b'[', b'5', b'~', ..]) => (Key::PageUp, 4),
+            (ESCAPE, [b'[', b'6', b'~', ..]) => (Key::PageDown, 4),
+            (byte, _) => (Key::Byte(byte), 1),
+        };
+        keys.push(key);
+        rest = &rest[length..];
+    }
+
+    keys
+}
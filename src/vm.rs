@@ -0,0 +1,904 @@
+use super::callstack::CallStack;
+use super::console::{Console, MemoryConsole};
+use super::device::Device;
+use super::error::{Fault, ImageError, VmError};
+use super::history::{Delta, History};
+use super::opcode::Opcode;
+use super::os;
+use super::profile::Profiler;
+use super::state::State;
+use super::trace::{TraceRecord, Tracer};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Address where execution starts, which is also where user programs are
+/// usually loaded.
+pub const PC_START: u16 = 0x3000;
+
+/// How long [`Vm::run`] waits before retrying an instruction that is waiting
+/// for input.
+pub(crate) const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// The result of executing a single instruction with [`Vm::step`].
+#[derive(Debug)]
+pub enum StepOutcome {
+    /// The instruction was executed and the machine is still running
+    Executed,
+    /// The machine is stopped, either by this instruction or a previous one
+    Halted,
+    /// A `GETC` or `IN` trap found no key to read. The PC is left pointing at
+    /// the TRAP, so stepping again retries it
+    WaitingForInput,
+    /// A TRAP other than a host serviced `HALT` was executed, carries the
+    /// trap vector. Through the trap vector table, the PC is now at the start
+    /// of its service routine
+    Trapped(u8),
+    /// The instruction raised an exception and the machine jumped to its
+    /// service routine, carries the exception vector
+    Exception(u8),
+    /// Instead of executing an instruction, the machine took an interrupt and
+    /// jumped to its service routine, carries the interrupt vector
+    Interrupted(u8),
+    /// The program tried to read a key after the console ran out of input,
+    /// which stops the machine
+    EndOfInput,
+    /// The instruction could not be executed. The PC points past it
+    Faulted(VmError),
+}
+
+/// What the VM does when an instruction raises an LC-3 exception, i.e. an
+/// illegal opcode, a privilege mode violation or an access control violation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionMode {
+    /// Stop and report a [`VmError`], which is what you want while debugging
+    /// a program or when no exception handlers are loaded
+    Error,
+    /// Enter the handler from the interrupt vector table, like the hardware
+    Dispatch,
+}
+
+/// How TRAP instructions are executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapMode {
+    /// The VM services `GETC`, `OUT`, `PUTS`, `IN`, `PUTSP` and `HALT` itself,
+    /// which is fast and needs no operating system in memory
+    Host,
+    /// Like the ISA specifies: R7 is loaded with the PC and execution jumps
+    /// to the service routine whose address is in the trap vector table, so
+    /// programs can install their own routines and traps
+    VectorTable,
+    /// Through the trap vector table as well, but the service routine runs in
+    /// supervisor mode: the PSR and PC are pushed onto the supervisor stack
+    /// like for an interrupt, and the routine returns with RTI. User mode
+    /// programs can then call routines in system space
+    Privileged,
+}
+
+/// Limits for [`Vm::run_with`], so that a program stuck in a loop or flooding
+/// the console cannot run forever. `None` means no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RunOptions {
+    /// Maximum number of instructions to execute, counting every interrupt
+    /// taken as one
+    pub max_instructions: Option<u64>,
+    /// Maximum number of bytes the program may write to the console. Anything
+    /// past it is dropped instead of reaching the console
+    pub max_output_bytes: Option<u64>,
+    /// Maximum wall-clock time to run for
+    pub max_duration: Option<Duration>,
+}
+
+/// One of the limits in [`RunOptions`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Instructions,
+    OutputBytes,
+    Duration,
+}
+
+/// Why [`Vm::run_with`] stopped.
+#[derive(Debug)]
+pub enum StopReason {
+    /// The program halted
+    Halted,
+    /// The program tried to read a key after the console ran out of input
+    EndOfInput,
+    /// The program was stopped because it hit a limit
+    LimitReached(Limit),
+    /// An instruction could not be executed
+    Faulted(VmError),
+}
+
+/// How a [`Vm::run_with`] run ended, along with what it used up and the final
+/// state of the machine.
+#[derive(Debug)]
+pub struct RunOutcome {
+    pub reason: StopReason,
+    /// Instructions executed, counting every interrupt taken as one
+    pub instructions: u64,
+    /// Bytes written to the console
+    pub output_bytes: u64,
+    /// Wall-clock time spent running
+    pub elapsed: Duration,
+    pub state: Box<State>,
+    /// Error writing the trace, if there was a tracer and it failed
+    pub trace_error: Option<std::io::Error>,
+    /// The profiler, if one was set, with the counts of the whole run
+    pub profiler: Option<Profiler>,
+}
+
+/// An LC-3 virtual machine: its [`State`] plus the fetch/decode/execute loop.
+#[derive(Debug)]
+pub struct Vm {
+    state: Box<State>,
+    exception_mode: ExceptionMode,
+    trap_mode: TrapMode,
+    call_stack: CallStack,
+    history: Option<History>,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+}
+
+impl Vm {
+    /// Creates a VM with zeroed memory and registers and the program counter
+    /// set to [`PC_START`], doing I/O through the terminal.
+    pub fn new() -> Vm {
+        Vm::from_state(State::new())
+    }
+
+    /// Like [`Vm::new`], but doing I/O through the given console.
+    pub fn with_console(console: Box<dyn Console>) -> Vm {
+        Vm::from_state(State::with_console(console))
+    }
+
+    fn from_state(state: State) -> Vm {
+        let mut state = Box::new(state);
+        state.pc = PC_START;
+
+        Vm {
+            state,
+            exception_mode: ExceptionMode::Error,
+            trap_mode: TrapMode::Host,
+            call_stack: CallStack::new(),
+            history: None,
+            tracer: None,
+            profiler: None,
+        }
+    }
+
+    /// Loads the bundled operating system into system space and switches to
+    /// executing TRAPs through its routines, in supervisor mode, and
+    /// dispatching exceptions to its handlers. Load user images afterwards, so they can replace any of its
+    /// vector table entries.
+    pub fn load_os(&mut self) {
+        self.load_image(&os::image())
+            .expect("the operating system fits in memory");
+        self.set_trap_mode(TrapMode::Privileged);
+        self.set_exception_mode(ExceptionMode::Dispatch);
+    }
+
+    /// Attaches a memory-mapped device, see [`crate::device::DeviceBus::attach`].
+    pub fn attach_device(&mut self, device: Box<dyn Device>) {
+        self.state.devices.attach(device);
+    }
+
+    /// Chooses how exceptions are handled, [`ExceptionMode::Error`] by default.
+    pub fn set_exception_mode(&mut self, exception_mode: ExceptionMode) {
+        self.exception_mode = exception_mode;
+    }
+
+    /// Chooses how TRAP instructions are executed, [`TrapMode::Host`] by
+    /// default.
+    pub fn set_trap_mode(&mut self, trap_mode: TrapMode) {
+        self.trap_mode = trap_mode;
+    }
+
+    /// Loads an image into memory. The first word of the image is the origin,
+    /// the rest is copied into memory starting at that address.
+    pub fn load_image(&mut self, image: &[u16]) -> Result<(), VmError> {
+        let (origin, words) = image.split_first().ok_or(ImageError::Empty)?;
+        let start = *origin as usize;
+
+        let destination = self
+            .state
+            .memory
+            .get_mut(start..start + words.len())
+            .ok_or(ImageError::DoesNotFit {
+                origin: *origin,
+                len: words.len(),
+            })?;
+        destination.copy_from_slice(words);
+
+        Ok(())
+    }
+
+    /// Reads an object file from disk and loads it with [`Vm::load_image`].
+    pub fn load_image_file(&mut self, file_path: &str) -> Result<(), VmError> {
+        let image = load_image_file(file_path).map_err(ImageError::Read)?;
+
+        self.load_image(&image)
+    }
+
+    /// Runs the loaded program until it halts, runs out of input or faults.
+    /// Whenever the program waits for a key, the VM sleeps briefly before
+    /// retrying.
+    pub fn run(&mut self) -> Result<(), VmError> {
+        loop {
+            match self.step() {
+                StepOutcome::Executed
+                | StepOutcome::Trapped(_)
+                | StepOutcome::Exception(_)
+                | StepOutcome::Interrupted(_) => {}
+                StepOutcome::WaitingForInput => std::thread::sleep(INPUT_POLL_INTERVAL),
+                StepOutcome::Halted | StepOutcome::EndOfInput => return Ok(()),
+                StepOutcome::Faulted(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Like [`Vm::run`], but stops as soon as the program hits one of the
+    /// limits in `options`. Consumes the VM to hand back its final state,
+    /// finishing the tracer if one is set and handing back the profiler.
+    pub fn run_with(mut self, options: &RunOptions) -> RunOutcome {
+        // The console is wrapped for the duration of the run to count the
+        // output, and handed back to the state afterwards.
+        let written = Rc::new(Cell::new(0));
+        let console = Rc::new(RefCell::new(std::mem::replace(
+            &mut self.state.console,
+            Box::new(MemoryConsole::new(&[])),
+        )));
+        self.state.console = Box::new(CountingConsole {
+            console: console.clone(),
+            written: written.clone(),
+            limit: options.max_output_bytes,
+        });
+
+        let started = Instant::now();
+        let mut instructions = 0;
+
+        let reason = loop {
+            if options
+                .max_instructions
+                .is_some_and(|limit| instructions >= limit)
+            {
+                break StopReason::LimitReached(Limit::Instructions);
+            }
+            if options
+                .max_duration
+                .is_some_and(|limit| started.elapsed() >= limit)
+            {
+                break StopReason::LimitReached(Limit::Duration);
+            }
+
+            // Taking an interrupt costs a step like an instruction does, so
+            // that interrupts can't keep a program going past the limit.
+            let running = self.state.running();
+            let outcome = self.step();
+            if running
+                && matches!(
+                    outcome,
+                    StepOutcome::Executed
+                        | StepOutcome::Trapped(_)
+                        | StepOutcome::Exception(_)
+                        | StepOutcome::Interrupted(_)
+                        | StepOutcome::Halted
+                        | StepOutcome::EndOfInput
+                )
+            {
+                instructions += 1;
+            }
+            if options
+                .max_output_bytes
+                .is_some_and(|limit| written.get() > limit)
+            {
+                break StopReason::LimitReached(Limit::OutputBytes);
+            }
+
+            match outcome {
+                StepOutcome::WaitingForInput => std::thread::sleep(INPUT_POLL_INTERVAL),
+                StepOutcome::Halted => break StopReason::Halted,
+                StepOutcome::EndOfInput => break StopReason::EndOfInput,
+                StepOutcome::Faulted(error) => break StopReason::Faulted(error),
+                _ => {}
+            }
+        };
+
+        // Dropping the counting console releases the original one.
+        self.state.console = Box::new(MemoryConsole::new(&[]));
+        self.state.console = Rc::try_unwrap(console)
+            .expect("only the counting console shares the console")
+            .into_inner();
+
+        let trace_error = self.tracer.take().and_then(|tracer| tracer.finish().err());
+
+        RunOutcome {
+            reason,
+            instructions,
+            output_bytes: options
+                .max_output_bytes
+                .map_or(written.get(), |limit| written.get().min(limit)),
+            elapsed: started.elapsed(),
+            state: self.state,
+            trace_error,
+            profiler: self.profiler,
+        }
+    }
+
+    /// Fetches, decodes and executes exactly one instruction, or takes a
+    /// pending interrupt.
+    pub fn step(&mut self) -> StepOutcome {
+        if !self.state.running() {
+            return StepOutcome::Halted;
+        }
+
+        let pc = self.state.pc;
+        let instruction = self.state.memory[pc as usize];
+        if self.history.is_none() && self.tracer.is_none() && self.profiler.is_none() {
+            let outcome = self.execute_step();
+            if !matches!(outcome, StepOutcome::WaitingForInput) {
+                self.call_stack
+                    .follow(pc, instruction, &outcome, &self.state);
+            }
+            return outcome;
+        }
+        let mut delta = Delta::capture(&self.state);
+        self.state.write_log = Some(Vec::new());
+        if self.profiler.is_some() {
+            self.state.access_log = Some(Vec::new());
+        }
+
+        let outcome = self.execute_step();
+
+        delta.writes = self.state.write_log.take().unwrap_or_default();
+        let accesses = self.state.access_log.take().unwrap_or_default();
+        // Waiting for input leaves everything as it was
+        if matches!(outcome, StepOutcome::WaitingForInput) {
+            return outcome;
+        }
+        delta.interrupted = matches!(outcome, StepOutcome::Interrupted(_));
+        delta.call = self
+            .call_stack
+            .follow(pc, instruction, &outcome, &self.state);
+        if let Some(tracer) = &mut self.tracer {
+            let interrupt = match outcome {
+                StepOutcome::Interrupted(vector) => Some(vector),
+                _ => None,
+            };
+            tracer.record(&TraceRecord::new(
+                &delta,
+                instruction,
+                interrupt,
+                &self.state,
+            ));
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(pc, &outcome, &self.call_stack, delta.call, &accesses);
+        }
+        if let Some(history) = &mut self.history {
+            history.push(delta);
+        }
+
+        outcome
+    }
+
+    /// Starts recording the last `capacity` steps, so they can be undone with
+    /// [`Vm::step_back`]. Any history recorded so far is dropped.
+    pub fn record_history(&mut self, capacity: usize) {
+        self.history = Some(History::new(capacity));
+    }
+
+    /// The steps recorded since [`Vm::record_history`] was called.
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Writes `values` to memory from `start` on, e.g. for a debugger. The
+    /// recorded history is forgotten, since undoing the steps from before the
+    /// write would leave the written words in place.
+    pub fn write_memory(&mut self, start: u16, values: &[u16]) {
+        for (address, value) in (start..=u16::MAX).zip(values) {
+            self.state.memory[address as usize] = *value;
+        }
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+    }
+
+    /// The subroutines and handlers the program is in, followed since the VM
+    /// was created.
+    pub fn call_stack(&self) -> &CallStack {
+        &self.call_stack
+    }
+
+    /// Starts writing a [`TraceRecord`] of every step to `tracer`.
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Stops tracing, handing back the tracer so it can be finished.
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    /// Starts counting where the program spends its time with `profiler`.
+    pub fn set_profiler(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    /// The profiler counting the steps, if one is set.
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Stops profiling, handing back the profiler with its counts.
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    /// Undoes the most recent recorded step. Returns `false` when there is no
+    /// step left to undo.
+    pub fn step_back(&mut self) -> bool {
+        match self.history.as_mut().and_then(History::pop) {
+            Some(delta) => {
+                delta.undo(&mut self.state);
+                self.call_stack.undo(delta.interrupted, delta.call);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn execute_step(&mut self) -> StepOutcome {
+        let result = match self.execute_instruction() {
+            Err(fault) if self.exception_mode == ExceptionMode::Dispatch => {
+                match fault.exception_vector() {
+                    Some(vector) => self
+                        .state
+                        .exception(vector)
+                        .map(|_| StepOutcome::Exception(vector)),
+                    None => Err(fault),
+                }
+            }
+            result => result,
+        };
+
+        match result {
+            Ok(_) if !self.state.running() && self.state.input_exhausted => StepOutcome::EndOfInput,
+            Ok(_) if !self.state.running() => StepOutcome::Halted,
+            Ok(outcome) => outcome,
+            Err(fault) => StepOutcome::Faulted(VmError::Fault {
+                fault,
+                snapshot: self.state.snapshot(),
+            }),
+        }
+    }
+
+    fn execute_instruction(&mut self) -> Result<StepOutcome, Fault> {
+        let state = &mut self.state;
+
+        if let Some(interrupt) = state.pending_interrupt()? {
+            state.interrupt(interrupt.vector, interrupt.priority)?;
+            return Ok(StepOutcome::Interrupted(interrupt.vector));
+        }
+
+        let instruction = state.fetch()?;
+
+        let opcode = instruction >> 12;
+        state.pc += 1;
+
+        match opcode.try_into() {
+            Ok(Opcode::BR) => state.conditional_branch(instruction)?,
+            Ok(Opcode::ADD) => state.add(instruction)?,
+            Ok(Opcode::LD) => state.load(instruction)?,
+            Ok(Opcode::ST) => state.store(instruction)?,
+            Ok(Opcode::JSR) => state.jump_to_subroutine(instruction)?,
+            Ok(Opcode::AND) => state.and(instruction)?,
+            Ok(Opcode::LDR) => state.load_base_plus_offset(instruction)?,
+            Ok(Opcode::STR) => state.store_base_plus_offset(instruction)?,
+            Ok(Opcode::RTI) => state.return_from_interrupt(instruction)?,
+            Ok(Opcode::NOT) => state.not(instruction)?,
+            Ok(Opcode::LDI) => state.load_indirect(instruction)?,
+            Ok(Opcode::STI) => state.store_indirect(instruction)?,
+            Ok(Opcode::JMP) => state.jump(instruction)?,
+            Ok(Opcode::LEA) => state.load_effective_address(instruction)?,
+            Ok(Opcode::TRAP) => match self.trap_mode {
+                TrapMode::Host => return state.trap(instruction),
+                TrapMode::VectorTable => return state.trap_through_table(instruction),
+                TrapMode::Privileged => return state.trap_to_supervisor(instruction),
+            },
+            Ok(Opcode::RES) | Err(_) => return Err(Fault::IllegalOpcode(instruction)),
+        }
+
+        Ok(StepOutcome::Executed)
+    }
+
+    /// The machine state: registers, flags and memory.
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Mutable access to the machine state, e.g. to poke memory or registers
+    /// before running.
+    pub fn state_mut(&mut self) -> &mut State {
+        &mut self.state
+    }
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counts the bytes written through a console for [`Vm::run_with`], dropping
+/// them once they go past the limit.
+struct CountingConsole {
+    console: Rc<RefCell<Box<dyn Console>>>,
+    written: Rc<Cell<u64>>,
+    limit: Option<u64>,
+}
+
+impl Console for CountingConsole {
+    fn read_byte(&mut self) -> std::io::Result<Option<u8>> {
+        self.console.borrow_mut().read_byte()
+    }
+
+    fn poll_key(&mut self) -> std::io::Result<bool> {
+        self.console.borrow_mut().poll_key()
+    }
+
+    fn write_byte(&mut self, byte: u8) -> std::io::Result<()> {
+        let written = self.written.get() + 1;
+        self.written.set(written);
+
+        if self.limit.is_some_and(|limit| written > limit) {
+            Ok(())
+        } else {
+            self.console.borrow_mut().write_byte(byte)
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.console.borrow_mut().flush()
+    }
+}
+
+/// Reads an LC-3 object file into a vector of words. The first word is the
+/// origin, i.e. the address the rest of the image should be loaded at.
+// Every single value needs to be swapped to account for big endianness
+pub fn load_image_file(file_path: &str) -> Result<Vec<u16>, std::io::Error> {
+    let contents = std::fs::read(file_path)?;
+
+    let buffer: Vec<u16> = contents
+        .chunks_exact(2)
+        .map(|a| u16::from_be_bytes([a[0], a[1]]))
+        .collect();
+
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard::{KEYBOARD_DATA_REGISTER, KEYBOARD_STATUS_REGISTER};
+    use crate::state::Privilege;
+    use crate::timer::{TIMER_CONTROL_REGISTER, TIMER_INTERVAL_REGISTER, TIMER_STATUS_REGISTER};
+
+    /// A VM dispatching exceptions, running `program` at 0x3000.
+    fn vm(program: &[u16]) -> Vm {
+        vm_reading(b"", program)
+    }
+
+    /// Like [`vm`], with `input` for the keyboard.
+    fn vm_reading(input: &[u8], program: &[u16]) -> Vm {
+        let mut vm = Vm::with_console(Box::new(MemoryConsole::new(input)));
+        let mut image = vec![0x3000];
+        image.extend(program);
+        vm.load_image(&image).unwrap();
+        vm.set_exception_mode(ExceptionMode::Dispatch);
+        vm
+    }
+
+    /// The PC and PSR an exception or interrupt pushed, in that order.
+    fn pushed(vm: &Vm) -> (u16, u16) {
+        let stack = vm.state().registers[6] as usize;
+        (vm.state().memory[stack], vm.state().memory[stack + 1])
+    }
+
+    #[test]
+    fn reading_past_the_end_of_the_input_stops_the_program() {
+        for read in [0xF020, 0xF023] {
+            // GETC or IN, then HALT
+            let mut vm = vm_reading(b"a", &[read, read, 0xF025]);
+
+            assert!(matches!(vm.step(), StepOutcome::Trapped(_)));
+            assert_eq!(vm.state().registers[0], b'a' as u16);
+            assert!(matches!(vm.step(), StepOutcome::EndOfInput));
+            assert!(!vm.state().running());
+            assert!(matches!(vm.step(), StepOutcome::Halted));
+        }
+    }
+
+    #[test]
+    fn polling_the_keyboard_past_the_end_of_the_input_stops_the_program() {
+        // LDI R0, KBSR; BRzp #-2; HALT; KBSR: .FILL xFE00
+        let mut vm = vm(&[0xA002, 0x07FE, 0xF025, KEYBOARD_STATUS_REGISTER]);
+
+        assert!(vm.run().is_ok());
+        assert!(vm.state().input_exhausted);
+        assert_eq!(vm.state().pc, 0x3001);
+    }
+
+    #[test]
+    fn runs_end_cleanly_at_the_end_of_the_input() {
+        let console = MemoryConsole::new(b"");
+        let mut vm = Vm::with_console(Box::new(console.clone()));
+        vm.load_image(&[0x3000, 0xF021, 0xF020, 0xF025]).unwrap(); // OUT, GETC, HALT
+
+        let outcome = vm.run_with(&RunOptions::default());
+
+        assert!(matches!(outcome.reason, StopReason::EndOfInput));
+        assert!(!outcome.state.running());
+        assert_eq!(console.output(), b"\0");
+    }
+
+    /// Runs `program` with `options`, along with the console it printed to.
+    fn run_with(program: &[u16], options: RunOptions) -> (RunOutcome, MemoryConsole) {
+        let console = MemoryConsole::new(&[]);
+        let mut vm = Vm::with_console(Box::new(console.clone()));
+        let mut image = vec![0x3000];
+        image.extend(program);
+        vm.load_image(&image).unwrap();
+
+        (vm.run_with(&options), console)
+    }
+
+    #[test]
+    fn run_with_counts_the_instructions_up_to_halt() {
+        // ADD R0, R0, #1 twice, then HALT
+        let (outcome, _) = run_with(&[0x1021, 0x1021, 0xF025], RunOptions::default());
+
+        assert!(matches!(outcome.reason, StopReason::Halted));
+        assert_eq!(outcome.instructions, 3);
+        assert_eq!(outcome.state.registers[0], 2);
+    }
+
+    #[test]
+    fn run_with_executes_nothing_once_halted() {
+        let mut vm = vm(&[0x1021]);
+        vm.state_mut().stop();
+
+        let outcome = vm.run_with(&RunOptions::default());
+
+        assert!(matches!(outcome.reason, StopReason::Halted));
+        assert_eq!(outcome.instructions, 0);
+    }
+
+    #[test]
+    fn run_with_stops_at_the_instruction_limit() {
+        let options = RunOptions {
+            max_instructions: Some(100),
+            ..RunOptions::default()
+        };
+        // ADD R0, R0, #1; BRnzp #-2
+        let (outcome, _) = run_with(&[0x1021, 0x0FFE], options);
+
+        assert!(matches!(
+            outcome.reason,
+            StopReason::LimitReached(Limit::Instructions)
+        ));
+        assert_eq!(outcome.instructions, 100);
+        assert_eq!(outcome.state.registers[0], 50);
+    }
+
+    #[test]
+    fn interrupts_count_towards_the_instruction_limit() {
+        let mut vm = spin_with_handler(b"", 0x81, TIMER_STATUS_REGISTER);
+        start_timer(&mut vm, 1);
+
+        // The interrupt, the handler's LDI and RTI, then the interrupt again.
+        let outcome = vm.run_with(&RunOptions {
+            max_instructions: Some(4),
+            ..RunOptions::default()
+        });
+
+        assert_eq!(outcome.instructions, 4);
+        assert_eq!(outcome.state.pc, 0x1000);
+        assert_eq!(outcome.state.priority(), 5);
+    }
+
+    #[test]
+    fn run_with_drops_the_output_past_the_limit() {
+        let options = RunOptions {
+            max_output_bytes: Some(5),
+            ..RunOptions::default()
+        };
+        // LD R0, CHARACTER; OUT; BRnzp #-2; CHARACTER: .FILL 'x'
+        let (outcome, console) = run_with(&[0x2002, 0xF021, 0x0FFE, b'x' as u16], options);
+
+        assert!(matches!(
+            outcome.reason,
+            StopReason::LimitReached(Limit::OutputBytes)
+        ));
+        assert_eq!(outcome.output_bytes, 5);
+        assert_eq!(console.output(), b"xxxxx");
+    }
+
+    #[test]
+    fn run_with_stops_at_the_timeout() {
+        let options = RunOptions {
+            max_duration: Some(Duration::from_millis(20)),
+            ..RunOptions::default()
+        };
+        // ADD R0, R0, #0 to set the condition codes, then BRnzp #-1
+        let (outcome, _) = run_with(&[0x1020, 0x0FFF], options);
+
+        assert!(matches!(
+            outcome.reason,
+            StopReason::LimitReached(Limit::Duration)
+        ));
+        assert!(outcome.elapsed >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn run_with_stops_at_a_fault() {
+        let (outcome, _) = run_with(&[0xD000], RunOptions::default());
+
+        assert!(matches!(outcome.reason, StopReason::Faulted(_)));
+        assert_eq!(outcome.instructions, 0);
+    }
+
+    #[test]
+    fn access_control_violations_enter_their_handler() {
+        let mut vm = vm(&[0x21FE]); // LD R0, x2FFF
+        vm.state_mut().memory[0x0102] = 0x1000;
+        vm.state_mut().set_privilege(Privilege::User);
+        let user_psr = vm.state().psr;
+        let user_stack = vm.state().registers[6];
+
+        assert!(matches!(vm.step(), StepOutcome::Exception(0x02)));
+        assert_eq!(vm.state().pc, 0x1000);
+        assert_eq!(vm.state().privilege(), Privilege::Supervisor);
+        assert_eq!(vm.state().registers[6], 0x3000 - 2);
+        assert_eq!(pushed(&vm), (0x3001, user_psr));
+
+        vm.state_mut().memory[0x1000] = 0x8000; // RTI
+        vm.step();
+        assert_eq!(vm.state().pc, 0x3001);
+        assert_eq!(vm.state().psr, user_psr);
+        assert_eq!(vm.state().registers[6], user_stack);
+    }
+
+    #[test]
+    fn rti_in_user_mode_is_a_privilege_mode_violation() {
+        let mut vm = vm(&[0x8000]); // RTI
+        vm.state_mut().memory[0x0100] = 0x1000;
+        vm.state_mut().set_privilege(Privilege::User);
+        let user_psr = vm.state().psr;
+
+        assert!(matches!(vm.step(), StepOutcome::Exception(0x00)));
+        assert_eq!(vm.state().pc, 0x1000);
+        assert_eq!(vm.state().privilege(), Privilege::Supervisor);
+        assert_eq!(pushed(&vm), (0x3001, user_psr));
+    }
+
+    #[test]
+    fn illegal_opcodes_keep_the_priority_level() {
+        let mut vm = vm(&[0xD000]); // reserved opcode
+        vm.state_mut().memory[0x0101] = 0x1000;
+        vm.state_mut().set_priority(3);
+        let psr = vm.state().psr;
+
+        assert!(matches!(vm.step(), StepOutcome::Exception(0x01)));
+        assert_eq!(vm.state().pc, 0x1000);
+        assert_eq!(vm.state().priority(), 3);
+        assert_eq!(vm.state().registers[6], 0x3000 - 2);
+        assert_eq!(pushed(&vm), (0x3001, psr));
+    }
+
+    #[test]
+    fn exceptions_are_errors_unless_dispatched() {
+        let mut vm = vm(&[0xD000]);
+        vm.set_exception_mode(ExceptionMode::Error);
+
+        assert!(matches!(vm.step(), StepOutcome::Faulted(_)));
+        assert_eq!(vm.state().registers[6], 0x3000);
+    }
+
+    /// Loops at 0x3000 in user mode, with a service routine for `vector` at
+    /// 0x1000 that reads the device register `register` and returns.
+    fn spin_with_handler(input: &[u8], vector: u8, register: u16) -> Vm {
+        let mut vm = vm_reading(input, &[0x0FFF]); // BRnzp #-1
+        let state = vm.state_mut();
+        state.memory[0x0100 + vector as usize] = 0x1000;
+        state.memory[0x1000] = 0xA001; // LDI R0, REGISTER
+        state.memory[0x1001] = 0x8000; // RTI
+        state.memory[0x1002] = register; // REGISTER: .FILL register
+        state.psr |= 0b010; // Z, which the branch needs to be taken
+        state.set_privilege(Privilege::User);
+        vm
+    }
+
+    fn start_timer(vm: &mut Vm, interval: u16) {
+        let state = vm.state_mut();
+        state
+            .system_write(TIMER_INTERVAL_REGISTER, interval)
+            .unwrap();
+        state.system_write(TIMER_CONTROL_REGISTER, 0xC000).unwrap();
+    }
+
+    #[test]
+    fn keyboard_interrupts_run_their_handler_and_rti_restores_the_program() {
+        let mut vm = spin_with_handler(b"a", 0x80, KEYBOARD_DATA_REGISTER);
+        let state = vm.state_mut();
+        state
+            .system_write(KEYBOARD_STATUS_REGISTER, 0x4000)
+            .unwrap();
+        let user_psr = state.psr;
+        let user_stack = state.registers[6];
+
+        assert!(matches!(vm.step(), StepOutcome::Interrupted(0x80)));
+        assert_eq!(vm.state().pc, 0x1000);
+        assert_eq!(vm.state().privilege(), Privilege::Supervisor);
+        assert_eq!(vm.state().priority(), 4);
+        assert_eq!(pushed(&vm), (0x3000, user_psr));
+
+        vm.step();
+        assert_eq!(vm.state().registers[0], b'a' as u16);
+        vm.step();
+        assert_eq!(vm.state().pc, 0x3000);
+        assert_eq!(vm.state().psr, user_psr);
+        assert_eq!(vm.state().registers[6], user_stack);
+
+        assert!(matches!(vm.step(), StepOutcome::Executed));
+    }
+
+    #[test]
+    fn interrupts_wait_for_a_lower_priority_level() {
+        let mut vm = spin_with_handler(b"", 0x81, TIMER_STATUS_REGISTER);
+        vm.state_mut().set_privilege(Privilege::Supervisor);
+        start_timer(&mut vm, 1);
+
+        vm.state_mut().set_priority(5);
+        assert!(matches!(vm.step(), StepOutcome::Executed));
+
+        vm.state_mut().set_priority(4);
+        let psr = vm.state().psr;
+        assert!(matches!(vm.step(), StepOutcome::Interrupted(0x81)));
+        assert_eq!(vm.state().priority(), 5);
+        assert_eq!(vm.state().registers[6], 0x3000 - 2);
+        assert_eq!(pushed(&vm), (0x3000, psr));
+
+        // The timer expires again while its handler runs at priority 5.
+        vm.step();
+        vm.step();
+        assert_eq!(vm.state().pc, 0x3000);
+        assert_eq!(vm.state().psr, psr);
+        assert!(matches!(vm.step(), StepOutcome::Interrupted(0x81)));
+    }
+
+    #[test]
+    fn the_highest_priority_interrupt_is_taken_first() {
+        let mut vm = spin_with_handler(b"a", 0x81, TIMER_STATUS_REGISTER);
+        vm.state_mut().memory[0x0180] = 0x1000;
+        let state = vm.state_mut();
+        state
+            .system_write(KEYBOARD_STATUS_REGISTER, 0x4000)
+            .unwrap();
+        let user_psr = state.psr;
+        start_timer(&mut vm, 1);
+
+        assert!(matches!(vm.step(), StepOutcome::Interrupted(0x81)));
+        vm.state_mut()
+            .system_write(TIMER_CONTROL_REGISTER, 0)
+            .unwrap();
+        vm.step();
+        vm.step();
+        assert_eq!(vm.state().psr, user_psr);
+
+        assert!(matches!(vm.step(), StepOutcome::Interrupted(0x80)));
+        assert_eq!(vm.state().priority(), 4);
+        assert_eq!(pushed(&vm), (0x3000, user_psr));
+    }
+}
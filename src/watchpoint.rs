@@ -0,0 +1,171 @@
+//! Watchpoints on the memory accesses a program makes.
+//!
+//! [`State::mem_read`](crate::State::mem_read) and
+//! [`State::mem_set`](crate::State::mem_set) check every access against
+//! [`State::watchpoints`](crate::State::watchpoints) and record the ones that
+//! match as [`WatchHit`]s. Instruction fetches are not watched.
+
+use std::ops::RangeInclusive;
+
+/// Whether an access read or wrote memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Which accesses a watchpoint stops on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    /// Every access
+    Always,
+    /// Writes that change the stored value
+    Changed,
+    /// Accesses reading or writing the given value
+    Equals(u16),
+}
+
+/// Watches a range of addresses for reads, writes or both.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addresses: RangeInclusive<u16>,
+    pub read: bool,
+    pub write: bool,
+    pub condition: Condition,
+}
+
+/// An access that matched a watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    /// Index of the watchpoint in [`State::watchpoints`](crate::State::watchpoints)
+    pub watchpoint: usize,
+    pub access: Access,
+    pub address: u16,
+    /// Value in memory before the access
+    pub old: u16,
+    /// Value read or written
+    pub value: u16,
+}
+
+impl Watchpoint {
+    /// Whether the watchpoint stops on an access of `address` that found `old`
+    /// in memory and read or wrote `value`.
+    pub fn matches(&self, access: Access, address: u16, old: u16, value: u16) -> bool {
+        let watched = match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+        };
+
+        watched
+            && self.addresses.contains(&address)
+            && match self.condition {
+                Condition::Always => true,
+                Condition::Changed => access == Access::Write && old != value,
+                Condition::Equals(expected) => value == expected,
+            }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::MemoryConsole;
+    use crate::timer::TIMER_INTERVAL_REGISTER;
+    use crate::State;
+
+    fn watchpoint(addresses: RangeInclusive<u16>, read: bool, write: bool) -> Watchpoint {
+        Watchpoint {
+            addresses,
+            read,
+            write,
+            condition: Condition::Always,
+        }
+    }
+
+    fn state(watchpoint: Watchpoint) -> State {
+        let mut state = State::with_console(Box::new(MemoryConsole::new(&[])));
+        state.watchpoints.push(watchpoint);
+        state
+    }
+
+    #[test]
+    fn watchpoints_match_their_accesses_and_addresses() {
+        let writes = watchpoint(0x3100..=0x3103, false, true);
+        assert!(writes.matches(Access::Write, 0x3100, 0, 1));
+        assert!(writes.matches(Access::Write, 0x3103, 0, 1));
+        assert!(!writes.matches(Access::Write, 0x3104, 0, 1));
+        assert!(!writes.matches(Access::Read, 0x3100, 0, 0));
+
+        let reads = watchpoint(0x3100..=0x3100, true, false);
+        assert!(reads.matches(Access::Read, 0x3100, 0, 0));
+        assert!(!reads.matches(Access::Write, 0x3100, 0, 1));
+
+        let accesses = watchpoint(0x3100..=0x3100, true, true);
+        assert!(accesses.matches(Access::Read, 0x3100, 0, 0));
+        assert!(accesses.matches(Access::Write, 0x3100, 0, 1));
+    }
+
+    #[test]
+    fn conditions_look_at_the_values() {
+        let changed = Watchpoint {
+            condition: Condition::Changed,
+            ..watchpoint(0x3100..=0x3100, true, true)
+        };
+        assert!(changed.matches(Access::Write, 0x3100, 1, 2));
+        assert!(!changed.matches(Access::Write, 0x3100, 2, 2));
+        assert!(!changed.matches(Access::Read, 0x3100, 2, 2));
+
+        let equals = Watchpoint {
+            condition: Condition::Equals(7),
+            ..watchpoint(0x3100..=0x3100, true, true)
+        };
+        assert!(equals.matches(Access::Write, 0x3100, 0, 7));
+        assert!(equals.matches(Access::Read, 0x3100, 7, 7));
+        assert!(!equals.matches(Access::Write, 0x3100, 7, 8));
+    }
+
+    #[test]
+    fn hits_report_the_old_and_new_values() {
+        let mut state = state(watchpoint(0x3100..=0x3101, true, true));
+        state.memory[0x3101] = 5;
+
+        state.mem_set(0x3101, 9).unwrap();
+        state.mem_read(0x3101).unwrap();
+        state.mem_set(0x3102, 1).unwrap();
+
+        assert_eq!(
+            state.watch_hits,
+            [
+                WatchHit {
+                    watchpoint: 0,
+                    access: Access::Write,
+                    address: 0x3101,
+                    old: 5,
+                    value: 9,
+                },
+                WatchHit {
+                    watchpoint: 0,
+                    access: Access::Read,
+                    address: 0x3101,
+                    old: 9,
+                    value: 9,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_to_device_registers_report_the_old_register_value() {
+        let mut state = state(watchpoint(
+            TIMER_INTERVAL_REGISTER..=TIMER_INTERVAL_REGISTER,
+            false,
+            true,
+        ));
+
+        state.mem_set(TIMER_INTERVAL_REGISTER, 100).unwrap();
+        state.mem_set(TIMER_INTERVAL_REGISTER, 200).unwrap();
+
+        let hit = state.watch_hits[1];
+        assert_eq!((hit.old, hit.value), (100, 200));
+    }
+}